@@ -10,12 +10,13 @@ use std::sync::{Arc, RwLock};
 use std::time::Duration;
 
 use electrs::{
+    chain::BlockHash,
     config::Config,
     daemon::Daemon,
     electrum::RPC as ElectrumRPC,
     errors::*,
     metrics::Metrics,
-    new_index::{precache, ChainQuery, FetchFrom, Indexer, Mempool, Query, Store},
+    new_index::{precache, ChainQuery, FetchFrom, IdleCompactor, Indexer, Mempool, Query, Store},
     rest,
     signal::Waiter,
 };
@@ -54,13 +55,21 @@ fn run_server(config: Arc<Config>) -> Result<()> {
         &metrics,
     )?);
     let store = Arc::new(Store::open(&config.db_path.join("newindex"), &config));
+    store.verify_history_encoding()?;
     let mut indexer = Indexer::open(
         Arc::clone(&store),
         fetch_from(&config, &store),
         &config,
         &metrics,
     );
-    let mut tip = indexer.update(&daemon)?;
+    // with --serve-only, blocks are indexed by a separate --index-only instance
+    let mut tip = if config.serve_only {
+        store.catch_up_with_primary();
+        BlockHash::default()
+    } else {
+        indexer.verify_headers(config.repair_headers)?;
+        indexer.update(&daemon)?
+    };
 
     let chain = Arc::new(ChainQuery::new(
         Arc::clone(&store),
@@ -69,6 +78,13 @@ fn run_server(config: Arc<Config>) -> Result<()> {
         &metrics,
     ));
 
+    if let Some(height) = config.prune_history_below_height {
+        info!("pruning history below height {}", height);
+        let pruned = chain.prune_history(height)?;
+        info!("pruned the history of {} scripts", pruned);
+        return Ok(());
+    }
+
     let mempool = Arc::new(RwLock::new(Mempool::new(
         Arc::clone(&chain),
         &metrics,
@@ -92,9 +108,14 @@ fn run_server(config: Arc<Config>) -> Result<()> {
         asset_db,
     ));
 
-    // TODO: configuration for which servers to start
-    let rest_server = rest::start(Arc::clone(&config), Arc::clone(&query));
-    let electrum_server = ElectrumRPC::start(Arc::clone(&config), Arc::clone(&query), &metrics);
+    let servers = if config.index_only {
+        None
+    } else {
+        Some((
+            rest::start(Arc::clone(&config), Arc::clone(&query)),
+            ElectrumRPC::start(Arc::clone(&config), Arc::clone(&query), &metrics),
+        ))
+    };
 
     if let Some(ref precache_file) = config.precache_scripts {
         let precache_scripthashes = precache::scripthashes_from_file(precache_file.to_string())
@@ -106,6 +127,8 @@ fn run_server(config: Arc<Config>) -> Result<()> {
         );
     }
 
+    let compactor = IdleCompactor::start(Arc::clone(&store), &config, &metrics);
+
     loop {
         if let Err(err) = signal.wait(Duration::from_millis(500), true) {
             info!("stopping server: {}", err);
@@ -123,23 +146,38 @@ fn run_server(config: Arc<Config>) -> Result<()> {
                 }
             });
 
-            rest_server.stop();
+            if let Some((rest_server, _)) = servers {
+                rest_server.stop();
+            }
             // the electrum server is stopped when dropped
             break;
         }
 
-        // Index new blocks
-        let current_tip = daemon.getbestblockhash()?;
-        if current_tip != tip {
-            indexer.update(&daemon)?;
-            tip = current_tip;
-        };
+        if config.serve_only {
+            // Pick up new blocks from the indexer instance
+            store.catch_up_with_primary();
+        } else {
+            // Index new blocks
+            let current_tip = daemon.getbestblockhash()?;
+            if current_tip != tip {
+                if let Some(ref compactor) = compactor {
+                    compactor.notify_activity();
+                }
+                indexer.update(&daemon)?;
+                tip = current_tip;
+                if let Some(ref compactor) = compactor {
+                    compactor.notify_activity();
+                }
+            };
+        }
 
-        // Update mempool
+        // Update mempool (serving instances track it on their own, it's not part of the DB)
         mempool.write().unwrap().update(&daemon)?;
 
         // Update subscribed clients
-        electrum_server.notify();
+        if let Some((_, ref electrum_server)) = servers {
+            electrum_server.notify();
+        }
     }
     info!("server stopped");
     Ok(())
@@ -4,12 +4,13 @@ use std::fs;
 use std::net::SocketAddr;
 use std::net::ToSocketAddrs;
 use std::path::{Path, PathBuf};
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 use stderrlog;
 
 use crate::chain::Network;
 use crate::daemon::CookieGetter;
 use crate::errors::*;
+use crate::util::fees::VSIZE_BIN_WIDTH;
 
 
 pub(crate) const APP_NAME: &str = "mempool-electrs";
@@ -43,19 +44,37 @@ pub struct Config {
     pub monitoring_addr: SocketAddr,
     pub jsonrpc_import: bool,
     pub light_mode: bool,
+    pub index_only: bool,
+    pub serve_only: bool,
+    pub prune_history_below_height: Option<usize>,
+    pub repair_headers: bool,
+    pub reindex_incompatible_dbs: bool,
+    pub idle_compaction_secs: u64,
+    pub idle_compaction_dbs: Vec<String>,
     pub address_search: bool,
     pub index_unspendables: bool,
     pub cors: Option<String>,
     pub precache_scripts: Option<String>,
     pub precache_threads: usize,
+    pub rest_worker_threads: usize,
     pub utxos_limit: usize,
     pub electrum_txs_limit: usize,
+    pub electrum_max_connections: usize,
+    pub electrum_max_inflight_queries: usize,
     pub electrum_banner: String,
     pub mempool_backlog_stats_ttl: u64,
+    pub fee_histogram_bin_width: u32,
+    pub fee_histogram_buckets: Vec<f32>,
     pub mempool_recent_txs_size: usize,
     pub rest_default_block_limit: usize,
     pub rest_default_chain_txs_per_page: usize,
     pub rest_default_max_mempool_txs: usize,
+    pub rest_mempool_page_size: usize,
+    pub rest_max_headers: usize,
+    pub rest_max_tip_wait: u64,
+    pub broadcast_rate_limit: usize,
+    pub broadcast_rate_window: u64,
+    pub broadcast_dedup_window: u64,
 
 
     #[cfg(feature = "electrum-discovery")]
@@ -78,6 +97,7 @@ fn str_to_socketaddr(address: &str, what: &str) -> SocketAddr {
 impl Config {
     pub fn from_args() -> Config {
         let network_help = format!("Select network type ({})", Network::names().join(", "));
+        let default_bin_width = VSIZE_BIN_WIDTH.to_string();
 
         let args = App::new("Electrum Rust Server")
             .version(crate_version!())
@@ -161,6 +181,48 @@ impl Config {
                     .long("lightmode")
                     .help("Enable light mode for reduced storage")
             )
+            .arg(
+                Arg::with_name("index_only")
+                    .long("index-only")
+                    .help("Only index new blocks, without starting the Electrum RPC and HTTP servers")
+            )
+            .arg(
+                Arg::with_name("serve_only")
+                    .long("serve-only")
+                    .help("Only serve requests, using the database of an --index-only instance running on the same db-dir (opened as a read-only RocksDB secondary). New blocks are picked up from the indexer, while the mempool is still polled from the daemon.")
+                    .conflicts_with("index_only")
+            )
+            .arg(
+                Arg::with_name("prune_history_below_height")
+                    .long("prune-history-below-height")
+                    .help("Maintenance: delete the address history confirmed below this height and exit. The height must be at least 10 blocks below the indexed tip. Balances and utxos stay correct, but the history of pruned addresses will be truncated.")
+                    .takes_value(true)
+                    .conflicts_with("serve_only")
+            )
+            .arg(
+                Arg::with_name("repair_headers")
+                    .long("repair-headers")
+                    .help("If the indexed headers fail the startup verification, roll the tip back to the last consistent block and re-fetch the rest from the daemon instead of exiting.")
+                    .conflicts_with("serve_only")
+            )
+            .arg(
+                Arg::with_name("reindex_incompatible_dbs")
+                    .long("reindex-incompatible-dbs")
+                    .help("Wipe and reindex the txstore or history DB if its schema version doesn't match this release, instead of exiting. The cache DB is rebuilt without it, unless the history was pruned.")
+                    .conflicts_with("serve_only")
+            )
+            .arg(
+                Arg::with_name("idle_compaction_secs")
+                    .long("idle-compaction-secs")
+                    .help("Run a background compaction of the DBs once no new blocks were indexed for this many seconds (0 = disabled). Stops early when new blocks arrive.")
+                    .default_value("0")
+            )
+            .arg(
+                Arg::with_name("idle_compaction_dbs")
+                    .long("idle-compaction-dbs")
+                    .help("Comma-separated list of DBs to compact when idle (txstore, history, cache)")
+                    .default_value("txstore,history,cache")
+            )
             .arg(
                 Arg::with_name("address_search")
                     .long("address-search")
@@ -189,6 +251,12 @@ impl Config {
                     .help("Non-zero number of threads to use for precache threadpool. [default: 4 * CORE_COUNT]")
                     .takes_value(true)
             )
+            .arg(
+                Arg::with_name("rest_worker_threads")
+                    .long("rest-worker-threads")
+                    .help("Non-zero number of worker threads for the HTTP server runtime. Parallel lookups done while serving requests (transaction and txo lookups) run on the global rayon pool, which also has CORE_COUNT threads, so raising this mostly helps when requests wait on I/O. [default: CORE_COUNT]")
+                    .takes_value(true)
+            )
             .arg(
                 Arg::with_name("utxos_limit")
                     .long("utxos-limit")
@@ -201,6 +269,18 @@ impl Config {
                     .help("The number of seconds that need to pass before Mempool::update will update the latency histogram again.")
                     .default_value("10")
             )
+            .arg(
+                Arg::with_name("fee_histogram_bin_width")
+                    .long("fee-histogram-bin-width")
+                    .help("The minimum total vsize of a mempool fee histogram bin, in vbytes. Lower values give a finer histogram.")
+                    .default_value(&default_bin_width)
+            )
+            .arg(
+                Arg::with_name("fee_histogram_buckets")
+                    .long("fee-histogram-buckets")
+                    .help("Comma-separated list of fixed fee rates (in sat/vbyte) to start the mempool fee histogram bins at, instead of bins sized by --fee-histogram-bin-width.")
+                    .takes_value(true)
+            )
             .arg(
                 Arg::with_name("mempool_recent_txs_size")
                     .long("mempool-recent-txs-size")
@@ -225,11 +305,57 @@ impl Config {
                     .help("The default number of mempool transactions returned by the txs endpoints.")
                     .default_value("50")
             )
+            .arg(
+                Arg::with_name("rest_mempool_page_size")
+                    .long("rest-mempool-page-size")
+                    .help("The number of transactions (or txids) returned per page by the paged mempool/txs and mempool/txids endpoints.")
+                    .default_value("10000")
+            )
+            .arg(
+                Arg::with_name("rest_max_headers")
+                    .long("rest-max-headers")
+                    .help("The maximum number of headers returned by the blocks/headers/[start_height]/[count] endpoint.")
+                    .default_value("2000")
+            )
+            .arg(
+                Arg::with_name("rest_max_tip_wait")
+                    .long("rest-max-tip-wait")
+                    .help("Maximum number of seconds the /blocks/tip/wait endpoint waits for a new block before returning the current tip height.")
+                    .default_value("60")
+            )
+            .arg(
+                Arg::with_name("broadcast_rate_limit")
+                    .long("broadcast-rate-limit")
+                    .help("Maximum number of transactions a single IP can broadcast through the HTTP API per --broadcast-rate-window (0 = unlimited). Limits by the TCP peer address, so don't enable it behind a reverse proxy. Not applied to unix socket connections.")
+                    .default_value("0")
+            )
+            .arg(
+                Arg::with_name("broadcast_rate_window")
+                    .long("broadcast-rate-window")
+                    .help("The window (in seconds) over which --broadcast-rate-limit is applied.")
+                    .default_value("60")
+            )
+            .arg(
+                Arg::with_name("broadcast_dedup_window")
+                    .long("broadcast-dedup-window")
+                    .help("Identical transactions broadcast again within this many seconds are not relayed to the daemon again (0 = disabled).")
+                    .default_value("30")
+            )
             .arg(
                 Arg::with_name("electrum_txs_limit")
                     .long("electrum-txs-limit")
                     .help("Maximum number of transactions returned by Electrum history queries. Lookups with more results will fail.")
                     .default_value("500")
+            ).arg(
+                Arg::with_name("electrum_max_connections")
+                    .long("electrum-max-connections")
+                    .help("Maximum number of concurrent Electrum connections. New connections beyond the limit are closed right away (0 = unlimited).")
+                    .default_value("0")
+            ).arg(
+                Arg::with_name("electrum_max_inflight_queries")
+                    .long("electrum-max-inflight-queries")
+                    .help("Maximum number of Electrum queries processed concurrently across all connections. Queries beyond the limit fail with a 'server busy' error. Subscriptions are not counted (0 = unlimited).")
+                    .default_value("0")
             ).arg(
                 Arg::with_name("electrum_banner")
                     .long("electrum-banner")
@@ -372,12 +498,33 @@ impl Config {
             utxos_limit: value_t_or_exit!(m, "utxos_limit", usize),
             electrum_rpc_addr,
             electrum_txs_limit: value_t_or_exit!(m, "electrum_txs_limit", usize),
+            electrum_max_connections: value_t_or_exit!(m, "electrum_max_connections", usize),
+            electrum_max_inflight_queries: value_t_or_exit!(
+                m,
+                "electrum_max_inflight_queries",
+                usize
+            ),
             electrum_banner,
             http_addr,
             http_socket_file,
             rpc_socket_file,
             monitoring_addr,
             mempool_backlog_stats_ttl: value_t_or_exit!(m, "mempool_backlog_stats_ttl", u64),
+            fee_histogram_bin_width: value_t_or_exit!(m, "fee_histogram_bin_width", u32),
+            fee_histogram_buckets: m
+                .value_of("fee_histogram_buckets")
+                .map_or_else(Vec::new, |s| {
+                    s.split(',')
+                        .map(|rate| match rate.trim().parse::<f32>() {
+                            Ok(rate) if rate.is_finite() && rate >= 0.0 => rate,
+                            _ => clap::Error::value_validation_auto(format!(
+                                "The argument '{}' isn't a valid fee rate",
+                                rate
+                            ))
+                            .exit(),
+                        })
+                        .collect()
+                }),
             mempool_recent_txs_size: value_t_or_exit!(m, "mempool_recent_txs_size", usize),
             rest_default_block_limit: value_t_or_exit!(m, "rest_default_block_limit", usize),
             rest_default_chain_txs_per_page: value_t_or_exit!(
@@ -390,12 +537,60 @@ impl Config {
                 "rest_default_max_mempool_txs",
                 usize
             ),
+            rest_mempool_page_size: value_t_or_exit!(m, "rest_mempool_page_size", usize),
+            rest_max_headers: value_t_or_exit!(m, "rest_max_headers", usize),
+            rest_max_tip_wait: value_t_or_exit!(m, "rest_max_tip_wait", u64),
+            broadcast_rate_limit: value_t_or_exit!(m, "broadcast_rate_limit", usize),
+            broadcast_rate_window: value_t_or_exit!(m, "broadcast_rate_window", u64),
+            broadcast_dedup_window: value_t_or_exit!(m, "broadcast_dedup_window", u64),
             jsonrpc_import: m.is_present("jsonrpc_import"),
             light_mode: m.is_present("light_mode"),
+            index_only: m.is_present("index_only"),
+            serve_only: m.is_present("serve_only"),
+            prune_history_below_height: m.value_of("prune_history_below_height").map(|s| {
+                s.parse().unwrap_or_else(|_| {
+                    clap::Error::value_validation_auto(format!(
+                        "The argument '{}' isn't a valid value",
+                        s
+                    ))
+                    .exit()
+                })
+            }),
+            idle_compaction_secs: value_t_or_exit!(m, "idle_compaction_secs", u64),
+            idle_compaction_dbs: m
+                .value_of("idle_compaction_dbs")
+                .unwrap()
+                .split(',')
+                .map(|s| match s.trim() {
+                    name @ ("txstore" | "history" | "cache") => name.to_string(),
+                    _ => clap::Error::value_validation_auto(format!(
+                        "The argument '{}' isn't a valid DB name",
+                        s
+                    ))
+                    .exit(),
+                })
+                .collect(),
             address_search: m.is_present("address_search"),
             index_unspendables: m.is_present("index_unspendables"),
+            repair_headers: m.is_present("repair_headers"),
+            reindex_incompatible_dbs: m.is_present("reindex_incompatible_dbs"),
             cors: m.value_of("cors").map(|s| s.to_string()),
             precache_scripts: m.value_of("precache_scripts").map(|s| s.to_string()),
+            rest_worker_threads: m.value_of("rest_worker_threads").map_or_else(
+                || {
+                    std::thread::available_parallelism()
+                        .expect("Can't get core count")
+                        .get()
+                },
+                |s| match s.parse::<usize>() {
+                    Ok(v) if v > 0 => v,
+                    _ => clap::Error::value_validation_auto(format!(
+                        "The argument '{}' isn't a valid value",
+                        s
+                    ))
+                    .exit(),
+                },
+            ),
             precache_threads: m.value_of("precache_threads").map_or_else(
                 || {
                     std::thread::available_parallelism()
@@ -421,9 +616,43 @@ impl Config {
             tor_proxy: m.value_of("tor_proxy").map(|s| s.parse().unwrap()),
         };
         eprintln!("{:?}", config);
+        if let Err(e) = config.validate() {
+            clap::Error::with_description(&e.to_string(), clap::ErrorKind::ArgumentConflict).exit()
+        }
         config
     }
 
+    /// Reject option combinations that would otherwise only misbehave at runtime,
+    /// and warn about options that have no effect.
+    pub fn validate(&self) -> Result<()> {
+        if self.serve_only {
+            // serving instances open the DBs read-only, writes are silently dropped
+            ensure!(
+                self.idle_compaction_secs == 0,
+                "--idle-compaction-secs has no effect with --serve-only, set it on the --index-only instance instead"
+            );
+            ensure!(
+                self.precache_scripts.is_none(),
+                "--precache-scripts has no effect with --serve-only, set it on the --index-only instance instead"
+            );
+            if self.jsonrpc_import {
+                warn!("--jsonrpc-import has no effect with --serve-only, blocks are indexed by the --index-only instance");
+            }
+        }
+        if !self.fee_histogram_buckets.is_empty() && self.fee_histogram_bin_width != VSIZE_BIN_WIDTH
+        {
+            warn!("--fee-histogram-bin-width is ignored when --fee-histogram-buckets is set");
+        }
+        if self.http_socket_file.is_some() && self.broadcast_rate_limit > 0 {
+            // there's no remote address to rate limit by on unix sockets
+            warn!("--broadcast-rate-limit does not apply to REST requests over --http-socket-file");
+        }
+        if self.address_search {
+            info!("--address-search only finds addresses indexed while it was enabled, enabling it on an existing index requires a reindex");
+        }
+        Ok(())
+    }
+
     pub fn cookie_getter(&self) -> Arc<dyn CookieGetter> {
         if let Some(ref value) = self.cookie {
             Arc::new(StaticCookie {
@@ -432,6 +661,7 @@ impl Config {
         } else {
             Arc::new(CookieFile {
                 daemon_dir: self.daemon_dir.clone(),
+                cookie: Mutex::new(None),
             })
         }
     }
@@ -449,10 +679,11 @@ impl CookieGetter for StaticCookie {
 
 struct CookieFile {
     daemon_dir: PathBuf,
+    cookie: Mutex<Option<Vec<u8>>>, // read on first use and re-read by reload()
 }
 
-impl CookieGetter for CookieFile {
-    fn get(&self) -> Result<Vec<u8>> {
+impl CookieFile {
+    fn read(&self) -> Result<Vec<u8>> {
         let path = self.daemon_dir.join(".cookie");
         let contents = fs::read(&path).chain_err(|| {
             ErrorKind::Connection(format!("failed to read cookie from {:?}", path))
@@ -460,3 +691,24 @@ impl CookieGetter for CookieFile {
         Ok(contents)
     }
 }
+
+impl CookieGetter for CookieFile {
+    fn get(&self) -> Result<Vec<u8>> {
+        let mut cookie = self.cookie.lock().unwrap();
+        if let Some(ref contents) = *cookie {
+            return Ok(contents.clone());
+        }
+        let contents = self.read()?;
+        *cookie = Some(contents.clone());
+        Ok(contents)
+    }
+
+    // the daemon writes a new cookie file every time it restarts
+    fn reload(&self) -> Result<bool> {
+        let contents = self.read()?;
+        let mut cookie = self.cookie.lock().unwrap();
+        let changed = cookie.as_ref() != Some(&contents);
+        *cookie = Some(contents);
+        Ok(changed)
+    }
+}
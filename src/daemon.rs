@@ -3,7 +3,7 @@ use std::io::{BufRead, BufReader, Lines, Write};
 use std::net::{SocketAddr, TcpStream};
 use std::path::PathBuf;
 use std::sync::{Arc, Mutex};
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 use base64;
 use tidecoin::hashes::hex::{FromHex, ToHex};
@@ -15,12 +15,17 @@ use serde_json::{from_str, from_value, Value};
 use tidecoin::consensus::encode::{deserialize, serialize};
 
 use crate::chain::{Block, BlockHash, BlockHeader, Network, Transaction, Txid};
-use crate::metrics::{HistogramOpts, HistogramVec, Metrics};
+use crate::metrics::{
+    Counter as MetricsCounter, Gauge, HistogramOpts, HistogramVec, MetricOpts, Metrics,
+};
 use crate::signal::Waiter;
 use crate::util::HeaderList;
 
 use crate::errors::*;
 
+// below this many new headers, walking back from the tip is cheaper than batching by height
+const BATCHED_HEADERS_MIN_GAP: usize = 100;
+
 fn parse_hash<T>(value: &Value) -> Result<T>
 where
     T: FromHex,
@@ -66,7 +71,11 @@ fn parse_jsonrpc_reply(mut reply: Value, method: &str, expected_id: u64) -> Resu
                     match code {
                         // RPC_IN_WARMUP -> retry by later reconnection
                         -28 => bail!(ErrorKind::Connection(err.to_string())),
-                        _ => bail!("{} RPC error: {}", method, err),
+                        _ => bail!(ErrorKind::RpcError(
+                            method.to_string(),
+                            code,
+                            err["message"].as_str().unwrap_or_default().to_string()
+                        )),
                     }
                 }
             }
@@ -116,6 +125,12 @@ struct NetworkInfo {
 
 pub trait CookieGetter: Send + Sync {
     fn get(&self) -> Result<Vec<u8>>;
+
+    /// Re-read the credentials, returning true if they have changed.
+    /// Static credentials are never reloaded.
+    fn reload(&self) -> Result<bool> {
+        Ok(false)
+    }
 }
 
 struct Connection {
@@ -124,6 +139,7 @@ struct Connection {
     cookie_getter: Arc<dyn CookieGetter>,
     addr: SocketAddr,
     signal: Waiter,
+    auth_reloads: MetricsCounter,
 }
 
 fn tcp_connect(addr: SocketAddr, signal: &Waiter) -> Result<TcpStream> {
@@ -144,6 +160,7 @@ impl Connection {
         addr: SocketAddr,
         cookie_getter: Arc<dyn CookieGetter>,
         signal: Waiter,
+        auth_reloads: MetricsCounter,
     ) -> Result<Connection> {
         let conn = tcp_connect(addr, &signal)?;
         let reader = BufReader::new(
@@ -156,11 +173,32 @@ impl Connection {
             cookie_getter,
             addr,
             signal,
+            auth_reloads,
         })
     }
 
     fn reconnect(&self) -> Result<Connection> {
-        Connection::new(self.addr, self.cookie_getter.clone(), self.signal.clone())
+        // pick up the new cookie if the daemon was restarted in the meantime. the cookie file
+        // may not exist yet while the daemon is starting up, so don't fail here: `send` reads
+        // it again and reports a missing cookie as a retryable connection error.
+        if let Err(e) = self.reload_cookie() {
+            warn!("failed to reload daemon RPC cookie: {}", e);
+        }
+        Connection::new(
+            self.addr,
+            self.cookie_getter.clone(),
+            self.signal.clone(),
+            self.auth_reloads.clone(),
+        )
+    }
+
+    fn reload_cookie(&self) -> Result<bool> {
+        let changed = self.cookie_getter.reload()?;
+        if changed {
+            info!("reloaded daemon RPC cookie");
+            self.auth_reloads.inc();
+        }
+        Ok(changed)
     }
 
     fn send(&mut self, request: &str) -> Result<()> {
@@ -192,6 +230,9 @@ impl Connection {
             let line = line.chain_err(|| ErrorKind::Connection("failed to read".to_owned()))?;
             if line.is_empty() {
                 in_header = false; // next line should contain the actual response.
+                if headers.get("Content-Length").map(String::as_str) == Some("0") {
+                    break; // no response to wait for (e.g. authentication errors)
+                }
             } else if in_header {
                 let parts: Vec<&str> = line.splitn(2, ": ").collect();
                 if parts.len() == 2 {
@@ -205,6 +246,16 @@ impl Connection {
             }
         }
 
+        if status == "HTTP/1.1 401 Unauthorized" {
+            if self.reload_cookie()? {
+                // retry by reconnecting with the new cookie
+                bail!(ErrorKind::Connection(
+                    "daemon RPC authentication failed, cookie was reloaded".to_owned()
+                ));
+            }
+            bail!("daemon RPC authentication failed");
+        }
+
         let contents =
             contents.chain_err(|| ErrorKind::Connection("no reply from daemon".to_owned()))?;
         let contents_length: &str = headers
@@ -268,6 +319,7 @@ pub struct Daemon {
     // monitoring
     latency: HistogramVec,
     size: HistogramVec,
+    header_rate: Gauge,
 }
 
 impl Daemon {
@@ -288,6 +340,10 @@ impl Daemon {
                 daemon_rpc_addr,
                 cookie_getter,
                 signal.clone(),
+                metrics.counter(MetricOpts::new(
+                    "daemon_auth_reloads",
+                    "# of times the daemon RPC cookie was reloaded",
+                )),
             )?),
             message_id: Counter::new(),
             signal: signal.clone(),
@@ -299,6 +355,10 @@ impl Daemon {
                 HistogramOpts::new("daemon_bytes", "Bitcoind RPC size (in bytes)"),
                 &["method", "dir"],
             ),
+            header_rate: metrics.gauge(MetricOpts::new(
+                "daemon_header_download_rate",
+                "Block headers downloaded per second during the last batched header sync",
+            )),
         };
         let network_info = daemon.getnetworkinfo()?;
         info!("{:?}", network_info);
@@ -357,6 +417,7 @@ impl Daemon {
             signal: self.signal.clone(),
             latency: self.latency.clone(),
             size: self.size.clone(),
+            header_rate: self.header_rate.clone(),
         })
     }
 
@@ -590,22 +651,38 @@ impl Daemon {
             .collect())
     }
 
-    fn get_all_headers(&self, tip: &BlockHash) -> Result<Vec<BlockHeader>> {
-        let info: Value = self.request("getblockheader", json!([tip.to_hex()]))?;
-        let tip_height = info
+    fn get_header_height(&self, blockhash: &BlockHash) -> Result<usize> {
+        let info: Value = self.request("getblockheader", json!([blockhash.to_hex()]))?;
+        let height = info
             .get("height")
-            .expect("missing height")
+            .chain_err(|| "missing height")?
             .as_u64()
-            .expect("non-numeric height") as usize;
-        let all_heights: Vec<usize> = (0..=tip_height).collect();
+            .chain_err(|| "non-numeric height")?;
+        Ok(height as usize)
+    }
+
+    // Downloads the headers at the given heights using batched RPC requests
+    fn get_headers_by_height(&self, heights: &[usize]) -> Result<Vec<BlockHeader>> {
         let chunk_size = 100_000;
+        let started = Instant::now();
         let mut result = vec![];
-        for heights in all_heights.chunks(chunk_size) {
+        for heights in heights.chunks(chunk_size) {
             trace!("downloading {} block headers", heights.len());
             let mut headers = self.getblockheaders(heights)?;
             assert!(headers.len() == heights.len());
             result.append(&mut headers);
         }
+        let elapsed = started.elapsed().as_secs_f64();
+        if elapsed > 0.0 {
+            self.header_rate.set((result.len() as f64 / elapsed) as i64);
+        }
+        Ok(result)
+    }
+
+    fn get_all_headers(&self, tip: &BlockHash) -> Result<Vec<BlockHeader>> {
+        let tip_height = self.get_header_height(tip)?;
+        let all_heights: Vec<usize> = (0..=tip_height).collect();
+        let result = self.get_headers_by_height(&all_heights)?;
 
         let mut blockhash = BlockHash::default();
         for header in &result {
@@ -616,6 +693,38 @@ impl Daemon {
         Ok(result)
     }
 
+    // Downloads the headers above the indexed tip in batches. Returns None if there are too few
+    // of them to be worth it, or if they don't connect to the indexed chain (e.g. on reorgs or
+    // if the tip moved while downloading).
+    fn get_headers_above(
+        &self,
+        indexed_headers: &HeaderList,
+        bestblockhash: &BlockHash,
+    ) -> Result<Option<Vec<BlockHeader>>> {
+        let tip_height = self.get_header_height(bestblockhash)?;
+        if tip_height.saturating_sub(indexed_headers.len()) < BATCHED_HEADERS_MIN_GAP {
+            return Ok(None);
+        }
+        let heights: Vec<usize> = (indexed_headers.len()..=tip_height).collect();
+        let headers = self.get_headers_by_height(&heights)?;
+
+        let mut blockhash = headers[0].prev_blockhash;
+        if indexed_headers.header_by_blockhash(&blockhash).is_none() {
+            return Ok(None);
+        }
+        for header in &headers {
+            if header.prev_blockhash != blockhash {
+                return Ok(None);
+            }
+            blockhash = header.block_hash();
+        }
+        Ok(if blockhash == *bestblockhash {
+            Some(headers)
+        } else {
+            None
+        })
+    }
+
     // Returns a list of BlockHeaders in ascending height (i.e. the tip is last).
     pub fn get_new_headers(
         &self,
@@ -632,6 +741,14 @@ impl Daemon {
             indexed_headers.len(),
             bestblockhash,
         );
+        if indexed_headers.header_by_blockhash(bestblockhash).is_some() {
+            return Ok(vec![]);
+        }
+        // Fast path: when far behind, fetch everything above our tip by height in a few batches
+        if let Some(new_headers) = self.get_headers_above(indexed_headers, bestblockhash)? {
+            trace!("downloaded {} block headers", new_headers.len());
+            return Ok(new_headers);
+        }
         let mut new_headers = vec![];
         let null_hash = BlockHash::default();
         let mut blockhash = *bestblockhash;
@@ -8,7 +8,7 @@ use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
 use std::os::unix::fs::FileTypeExt;
 use std::os::unix::net::{UnixListener, UnixStream};
 use std::path::Path;
-use std::sync::atomic::AtomicBool;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::sync::mpsc::{Receiver, Sender};
 use std::sync::{Arc, Mutex};
 use std::thread;
@@ -25,8 +25,8 @@ use crate::chain::Txid;
 use crate::config::{Config, VERSION_STRING};
 use crate::electrum::{get_electrum_height, ProtocolVersion};
 use crate::errors::*;
-use crate::metrics::{Gauge, HistogramOpts, HistogramVec, MetricOpts, Metrics};
-use crate::new_index::{Query, Utxo};
+use crate::metrics::{Counter, Gauge, HistogramOpts, HistogramVec, MetricOpts, Metrics};
+use crate::new_index::{DBFlush, Query, Utxo};
 use crate::util::electrum_merkle::{get_header_merkle_proof, get_id_from_pos, get_tx_merkle_proof};
 use crate::util::{
     create_socket, full_hash, spawn_thread, BlockId, BoolThen, Channel, FullHash, HeaderEntry,
@@ -104,6 +104,7 @@ struct Connection {
     chan: SyncChannel<Message>,
     stats: Arc<Stats>,
     txs_limit: usize,
+    inflight: Arc<InflightLimit>,
     die_please: Option<Receiver<()>>,
     #[cfg(feature = "electrum-discovery")]
     discovery: Option<Arc<DiscoveryManager>>,
@@ -115,6 +116,7 @@ impl Connection {
         stream: ConnectionStream,
         stats: Arc<Stats>,
         txs_limit: usize,
+        inflight: Arc<InflightLimit>,
         die_please: Receiver<()>,
         #[cfg(feature = "electrum-discovery")] discovery: Option<Arc<DiscoveryManager>>,
     ) -> Connection {
@@ -126,6 +128,7 @@ impl Connection {
             chan: SyncChannel::new(10),
             stats,
             txs_limit,
+            inflight,
             die_please: Some(die_please),
             #[cfg(feature = "electrum-discovery")]
             discovery,
@@ -208,7 +211,7 @@ impl Connection {
             .chain()
             .header_by_height(height)
             .map(|entry| hex::encode(serialize(entry.header())))
-            .chain_err(|| "missing header")?;
+            .chain_err(|| format!("no block header at height {}", height))?;
 
         if cp_height == 0 {
             return Ok(json!(raw_header_hex));
@@ -226,7 +229,7 @@ impl Connection {
         let start_height = usize_from_value(params.get(0), "start_height")?;
         let count = MAX_HEADERS.min(usize_from_value(params.get(1), "count")?);
         let cp_height = usize_from_value_or(params.get(2), "cp_height", 0)?;
-        let heights: Vec<usize> = (start_height..(start_height + count)).collect();
+        let heights: Vec<usize> = (start_height..start_height.saturating_add(count)).collect();
         let headers: Vec<String> = heights
             .into_iter()
             .filter_map(|height| {
@@ -292,7 +295,7 @@ impl Connection {
 
     fn blockchain_scripthash_get_balance(&self, params: &[Value]) -> Result<Value> {
         let script_hash = hash_from_value(params.get(0)).chain_err(|| "bad script_hash")?;
-        let (chain_stats, mempool_stats) = self.query.stats(&script_hash[..]);
+        let (chain_stats, mempool_stats) = self.query.stats(&script_hash[..])?;
 
         Ok(json!({
             "confirmed": chain_stats.funded_txo_sum - chain_stats.spent_txo_sum,
@@ -318,9 +321,22 @@ impl Connection {
             .collect::<Vec<_>>()))
     }
 
+    fn blockchain_scripthash_get_first_use(&self, params: &[Value]) -> Result<Value> {
+        let script_hash = hash_from_value(params.get(0)).chain_err(|| "bad script_hash")?;
+        // only confirmed history is considered, null if the scripthash was never used
+        Ok(match self.query.chain().get_first_use(&script_hash[..]) {
+            Some((txid, blockid)) => json!({
+                "tx_hash": txid,
+                "height": blockid.height,
+                "block_hash": blockid.hash,
+            }),
+            None => Value::Null,
+        })
+    }
+
     fn blockchain_scripthash_listunspent(&self, params: &[Value]) -> Result<Value> {
         let script_hash = hash_from_value(params.get(0)).chain_err(|| "bad script_hash")?;
-        let utxos = self.query.utxo(&script_hash[..])?;
+        let utxos = self.query.utxo(&script_hash[..], DBFlush::Enable)?;
 
         let to_json = |utxo: Utxo| {
             let json = json!({
@@ -403,6 +419,18 @@ impl Connection {
     }
 
     fn handle_command(&mut self, method: &str, params: &[Value], id: &Value) -> Result<Value> {
+        // subscriptions are cheap to register and must not be starved by other queries
+        let _guard = if method.ends_with(".subscribe") {
+            None
+        } else {
+            match self.inflight.try_acquire() {
+                Some(guard) => Some(guard),
+                None => {
+                    self.stats.rejected_queries.inc();
+                    return Ok(json!({"jsonrpc": "2.0", "id": id, "error": "server busy"}));
+                }
+            }
+        };
         let timer = self
             .stats
             .latency
@@ -415,6 +443,9 @@ impl Connection {
             "blockchain.headers.subscribe" => self.blockchain_headers_subscribe(),
             "blockchain.relayfee" => self.blockchain_relayfee(),
             "blockchain.scripthash.get_balance" => self.blockchain_scripthash_get_balance(params),
+            "blockchain.scripthash.get_first_use" => {
+                self.blockchain_scripthash_get_first_use(params)
+            }
             "blockchain.scripthash.get_history" => self.blockchain_scripthash_get_history(params),
             "blockchain.scripthash.listunspent" => self.blockchain_scripthash_listunspent(params),
             "blockchain.scripthash.subscribe" => self.blockchain_scripthash_subscribe(params),
@@ -573,7 +604,6 @@ impl Connection {
     }
 
     pub fn run(mut self) {
-        self.stats.clients.inc();
         let reader = BufReader::new(self.stream.try_clone().expect("failed to clone TcpStream"));
         let tx = self.chan.sender();
 
@@ -600,7 +630,6 @@ impl Connection {
                 e.display_chain().to_string()
             );
         }
-        self.stats.clients.dec();
         self.stats
             .subscriptions
             .sub(self.status_hashes.len() as i64);
@@ -657,6 +686,40 @@ struct Stats {
     latency: HistogramVec,
     clients: Gauge,
     subscriptions: Gauge,
+    rejected_connections: Counter,
+    rejected_queries: Counter,
+}
+
+/// Limits the number of queries processed concurrently across all connections
+struct InflightLimit {
+    current: AtomicUsize,
+    max: usize, // 0 = unlimited
+}
+
+struct InflightGuard<'a>(&'a AtomicUsize);
+
+impl InflightLimit {
+    fn new(max: usize) -> Self {
+        InflightLimit {
+            current: AtomicUsize::new(0),
+            max,
+        }
+    }
+
+    fn try_acquire(&self) -> Option<InflightGuard> {
+        let prev = self.current.fetch_add(1, Ordering::SeqCst);
+        if self.max > 0 && prev >= self.max {
+            self.current.fetch_sub(1, Ordering::SeqCst);
+            return None;
+        }
+        Some(InflightGuard(&self.current))
+    }
+}
+
+impl Drop for InflightGuard<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
 }
 
 impl RPC {
@@ -723,6 +786,14 @@ impl RPC {
                 "electrum_subscriptions",
                 "# of Electrum subscriptions",
             )),
+            rejected_connections: metrics.counter(MetricOpts::new(
+                "electrum_rejected_connections",
+                "# of Electrum connections rejected due to --electrum-max-connections",
+            )),
+            rejected_queries: metrics.counter(MetricOpts::new(
+                "electrum_rejected_queries",
+                "# of Electrum queries rejected due to --electrum-max-inflight-queries",
+            )),
         });
         stats.clients.set(0);
         stats.subscriptions.set(0);
@@ -754,6 +825,8 @@ impl RPC {
         });
 
         let txs_limit = config.electrum_txs_limit;
+        let max_connections = config.electrum_max_connections;
+        let inflight = Arc::new(InflightLimit::new(config.electrum_max_inflight_queries));
 
         RPC {
             notification: notification.sender(),
@@ -776,10 +849,19 @@ impl RPC {
 
                 while let Some(stream) = acceptor.receiver().recv().unwrap() {
                     let addr = stream.addr_string();
+                    if max_connections > 0 && stats.clients.get() >= max_connections as i64 {
+                        warn!("[{}] too many connections, rejecting peer", addr);
+                        stats.rejected_connections.inc();
+                        let _ = stream.shutdown(Shutdown::Both);
+                        continue;
+                    }
+                    // counted here rather than in the peer thread, so that the limit can't be overshot
+                    stats.clients.inc();
                     // explicitely scope the shadowed variables for the new thread
                     let query = Arc::clone(&query);
                     let senders = Arc::clone(&senders);
                     let stats = Arc::clone(&stats);
+                    let inflight = Arc::clone(&inflight);
                     let garbage_sender = garbage_sender.clone();
 
                     // Kill the peers properly
@@ -795,14 +877,16 @@ impl RPC {
                         let conn = Connection::new(
                             query,
                             stream,
-                            stats,
+                            Arc::clone(&stats),
                             txs_limit,
+                            inflight,
                             peace_receiver,
                             #[cfg(feature = "electrum-discovery")]
                             discovery,
                         );
                         senders.lock().unwrap().push(conn.chan.sender());
                         conn.run();
+                        stats.clients.dec();
                         info!("[{}] disconnected peer", addr);
                         let _ = killer_clone.send(());
                         let _ = garbage_sender.send(std::thread::current().id());
@@ -926,6 +1010,11 @@ impl ConnectionListener {
                 .expect("failed to set connection as blocking");
             acceptor.send(Some(stream)).expect("send failed");
         }
+
+        if let Self::Unix(_, path) = self {
+            // Don't leave a stale socket file behind
+            fs::remove_file(path).ok();
+        }
     }
 
     fn accept(&self) -> std::result::Result<ConnectionStream, std::io::Error> {
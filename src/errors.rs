@@ -14,6 +14,11 @@ error_chain! {
             display("Iterrupted by signal {}", sig)
         }
 
+        RpcError(method: String, code: i64, message: String) {
+            description("RPC error")
+            display("{} RPC error: {} (code {})", method, message, code)
+        }
+
         TooPopular {
             description("Too many history entries")
             display("Too many history entries")
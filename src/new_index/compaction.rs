@@ -0,0 +1,90 @@
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::config::Config;
+use crate::metrics::{GaugeVec, MetricOpts, Metrics};
+use crate::new_index::{Store, DB};
+use crate::util::spawn_thread;
+
+const CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Compacts the DBs in the background once no new blocks were indexed for a while,
+/// clearing out tombstones faster than RocksDB's auto compaction would.
+pub struct IdleCompactor {
+    last_activity: Arc<Mutex<Instant>>,
+}
+
+impl IdleCompactor {
+    /// Returns None if idle compaction is disabled
+    pub fn start(store: Arc<Store>, config: &Config, metrics: &Metrics) -> Option<Self> {
+        if config.idle_compaction_secs == 0 {
+            return None;
+        }
+        let idle_after = Duration::from_secs(config.idle_compaction_secs);
+        let dbs = config.idle_compaction_dbs.clone();
+        let last_compaction = metrics.gauge_vec(
+            MetricOpts::new(
+                "last_compaction_timestamp",
+                "Unix time of the last completed idle compaction",
+            ),
+            &["db"],
+        );
+
+        let last_activity = Arc::new(Mutex::new(Instant::now()));
+        let activity = Arc::clone(&last_activity);
+        spawn_thread("idle-compaction", move || {
+            let mut compacted_at: Option<Instant> = None;
+            loop {
+                thread::sleep(CHECK_INTERVAL);
+                let since = *activity.lock().unwrap();
+                // compact once per idle period
+                if since.elapsed() < idle_after || compacted_at.map_or(false, |t| t > since) {
+                    continue;
+                }
+                let done = dbs.iter().all(|name| {
+                    let db = match name.as_str() {
+                        "txstore" => store.txstore_db(),
+                        "history" => store.history_db(),
+                        "cache" => store.cache_db(),
+                        _ => unreachable!("invalid db name"),
+                    };
+                    info!("starting idle compaction of the {} db", name);
+                    if !compact_while_idle(db, &activity, since) {
+                        info!("new blocks arrived, postponing idle compaction");
+                        return false;
+                    }
+                    let now = SystemTime::now()
+                        .duration_since(UNIX_EPOCH)
+                        .map_or(0, |d| d.as_secs());
+                    last_compaction.with_label_values(&[name]).set(now as f64);
+                    info!("finished idle compaction of the {} db", name);
+                    true
+                });
+                if done {
+                    compacted_at = Some(Instant::now());
+                }
+            }
+        });
+
+        Some(IdleCompactor { last_activity })
+    }
+
+    /// Postpone compactions until the DBs are idle again. Should be called before and after indexing.
+    pub fn notify_activity(&self) {
+        *self.last_activity.lock().unwrap() = Instant::now();
+    }
+}
+
+// Compact one two-byte key prefix at a time, stopping early if there was any activity since
+// `since`. Single-byte prefixes would be too coarse, e.g. nearly all of the history db is under `H`.
+fn compact_while_idle(db: &DB, activity: &Mutex<Instant>, since: Instant) -> bool {
+    for prefix in 0..=u16::MAX {
+        if *activity.lock().unwrap() != since {
+            return false;
+        }
+        let end = prefix.checked_add(1).map(u16::to_be_bytes);
+        db.compact_range(&prefix.to_be_bytes(), end.as_ref().map(|end| &end[..]));
+    }
+    true
+}
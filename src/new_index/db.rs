@@ -5,8 +5,6 @@ use std::path::Path;
 use crate::config::Config;
 use crate::util::{bincode_util, Bytes};
 
-static DB_VERSION: u32 = 1;
-
 #[derive(Debug, Eq, PartialEq)]
 pub struct DBRow {
     pub key: Vec<u8>,
@@ -72,6 +70,8 @@ impl<'a> Iterator for ReverseScanIterator<'a> {
 #[derive(Debug)]
 pub struct DB {
     db: rocksdb::DB,
+    // opened as a RocksDB secondary instance (--serve-only), writes are skipped
+    secondary: bool,
 }
 
 #[derive(Copy, Clone, Debug)]
@@ -81,12 +81,58 @@ pub enum DBFlush {
 }
 
 impl DB {
-    pub fn open(path: &Path, config: &Config) -> DB {
-        let db = DB {
-            db: open_raw_db(path),
+    /// Open the DB, which must have been written with the given schema `version`. With
+    /// `wipe_incompatible`, an incompatible DB is wiped so that the indexer rebuilds it.
+    pub fn open(path: &Path, version: u32, wipe_incompatible: bool, config: &Config) -> DB {
+        let db = if config.serve_only {
+            DB {
+                db: open_secondary_db(path),
+                secondary: true,
+            }
+        } else {
+            DB {
+                db: open_raw_db(path),
+                secondary: false,
+            }
         };
-        db.verify_compatibility(config);
-        db
+        if db.is_compatible(version, config) {
+            return db;
+        }
+        if db.secondary {
+            panic!(
+                "Incompatible database found at {:?}, start the indexer (--index-only) first to upgrade it.",
+                path
+            );
+        }
+        if !wipe_incompatible {
+            panic!(
+                "Incompatible database found at {:?}. Please reindex, or run with --reindex-incompatible-dbs to rebuild it.",
+                path
+            );
+        }
+        warn!("wiping incompatible database at {:?} to reindex it", path);
+        drop(db);
+        rocksdb::DB::destroy(&rocksdb::Options::default(), path)
+            .expect("failed to wipe incompatible RocksDB");
+        DB::open(path, version, false, config)
+    }
+
+    #[cfg(test)]
+    pub fn open_unversioned(path: &Path) -> DB {
+        DB {
+            db: open_raw_db(path),
+            secondary: false,
+        }
+    }
+
+    /// Replay the changes made by the primary instance since the last call.
+    /// Only applies to DBs opened with --serve-only.
+    pub fn catch_up(&self) {
+        if self.secondary {
+            if let Err(e) = self.db.try_catch_up_with_primary() {
+                warn!("failed catching up with primary {:?}: {}", self.db, e);
+            }
+        }
     }
 
     pub fn full_compaction(&self) {
@@ -96,6 +142,11 @@ impl DB {
         debug!("finished full compaction on {:?}", self.db);
     }
 
+    /// Compact the keys in [start, end), or from start onwards if there's no end
+    pub fn compact_range(&self, start: &[u8], end: Option<&[u8]>) {
+        self.db.compact_range(Some(start), end);
+    }
+
     pub fn enable_auto_compaction(&self) {
         let opts = [("disable_auto_compactions", "false")];
         self.db.set_options(&opts).unwrap();
@@ -137,6 +188,14 @@ impl DB {
     }
 
     pub fn write(&self, mut rows: Vec<DBRow>, flush: DBFlush) {
+        if self.secondary {
+            debug!(
+                "skipping write of {} rows to secondary {:?}",
+                rows.len(),
+                self.db
+            );
+            return;
+        }
         debug!(
             "writing {} rows to {:?}, flush={:?}",
             rows.len(),
@@ -158,15 +217,35 @@ impl DB {
         self.db.write_opt(batch, &opts).unwrap();
     }
 
+    pub fn delete(&self, keys: Vec<Bytes>) {
+        if self.secondary {
+            return;
+        }
+        debug!("deleting {} rows from {:?}", keys.len(), self.db);
+        let mut batch = rocksdb::WriteBatch::default();
+        for key in keys {
+            batch.delete(&key);
+        }
+        let mut opts = rocksdb::WriteOptions::new();
+        opts.set_sync(true);
+        self.db.write_opt(batch, &opts).unwrap();
+    }
+
     pub fn flush(&self) {
         self.db.flush().unwrap();
     }
 
     pub fn put(&self, key: &[u8], value: &[u8]) {
+        if self.secondary {
+            return;
+        }
         self.db.put(key, value).unwrap();
     }
 
     pub fn put_sync(&self, key: &[u8], value: &[u8]) {
+        if self.secondary {
+            return;
+        }
         let mut opts = rocksdb::WriteOptions::new();
         opts.set_sync(true);
         self.db.put_opt(key, value, &opts).unwrap();
@@ -176,23 +255,27 @@ impl DB {
         self.db.get(key).unwrap().map(|v| v.to_vec())
     }
 
-    fn verify_compatibility(&self, config: &Config) {
-        let mut compatibility_bytes = bincode_util::serialize_little(&DB_VERSION).unwrap();
+    // new DBs get the given version written to them
+    fn is_compatible(&self, version: u32, config: &Config) -> bool {
+        let mut compatibility_bytes = bincode_util::serialize_little(&version).unwrap();
 
         if config.light_mode {
             // append a byte to indicate light_mode is enabled.
             // we're not letting bincode serialize this so that the compatiblity bytes won't change
             // (and require a reindex) when light_mode is disabled. this should be chagned the next
-            // time we bump DB_VERSION and require a re-index anyway.
+            // time we bump the DB versions and require a re-index anyway.
             compatibility_bytes.push(1);
         }
 
         match self.get(b"V") {
-            None => self.put(b"V", &compatibility_bytes),
-            Some(ref x) if x != &compatibility_bytes => {
-                panic!("Incompatible database found. Please reindex.")
+            None if self.secondary => {
+                panic!("Database not initialized yet, start the indexer (--index-only) first.")
             }
-            Some(_) => (),
+            None => {
+                self.put(b"V", &compatibility_bytes);
+                true
+            }
+            Some(ref x) => x == &compatibility_bytes,
         }
     }
 }
@@ -217,3 +300,19 @@ pub fn open_raw_db<T: rocksdb::ThreadMode>(path: &Path) -> rocksdb::DBWithThread
 
     rocksdb::DBWithThreadMode::<T>::open(&db_opts, path).expect("failed to open RocksDB")
 }
+
+fn open_secondary_db(path: &Path) -> rocksdb::DB {
+    // secondary instances need a directory of their own for their info logs,
+    // which must not be shared with the primary or with other secondaries.
+    let secondary_path = std::env::temp_dir()
+        .join(format!("electrs-secondary-{}", std::process::id()))
+        .join(path.file_name().expect("invalid DB path"));
+    debug!(
+        "opening DB at {:?} as secondary ({:?})",
+        path, secondary_path
+    );
+    let mut db_opts = rocksdb::Options::default();
+    db_opts.set_max_open_files(-1); // required for secondary instances
+    rocksdb::DB::open_as_secondary(&db_opts, path, secondary_path.as_path())
+        .expect("failed to open RocksDB as secondary")
+}
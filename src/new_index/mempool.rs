@@ -4,13 +4,13 @@ use itertools::Itertools;
 use tidecoin::consensus::encode::serialize;
 
 
-use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque};
 use std::iter::FromIterator;
 use std::ops::Bound::{Excluded, Unbounded};
 use std::sync::Arc;
-use std::time::{Duration, Instant};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
-use crate::chain::{deserialize, Network, OutPoint, Transaction, TxOut, Txid};
+use crate::chain::{deserialize, BlockHash, Network, OutPoint, Transaction, TxOut, Txid};
 use crate::config::Config;
 use crate::daemon::Daemon;
 use crate::errors::*;
@@ -20,8 +20,9 @@ use crate::new_index::{
     SpendingInput, TxHistoryInfo, Utxo,
 };
 use crate::util::fees::{make_fee_histogram, TxFeeInfo};
-use crate::util::{extract_tx_prevouts, full_hash, has_prevout, is_spendable, Bytes};
+use crate::util::{extract_tx_prevouts, full_hash, has_prevout, is_spendable, Bytes, HeaderList};
 
+const RECENT_REPLACEMENTS_SIZE: usize = 10_000;
 
 pub struct Mempool {
     chain: Arc<ChainQuery>,
@@ -31,6 +32,10 @@ pub struct Mempool {
     history: HashMap<FullHash, Vec<TxHistoryInfo>>, // ScriptHash -> {history_entries}
     edges: HashMap<OutPoint, (Txid, u32)>,          // OutPoint -> (spending_txid, spending_vin)
     recent: BoundedVecDeque<TxOverview>,            // The N most recent txs to enter the mempool
+    first_seen: HashMap<Txid, u64>,                 // Txid -> unix time the tx was first seen
+    replacements: HashMap<Txid, Txid>,              // Replaced txid -> replacing txid (RBF)
+    replacements_order: VecDeque<Txid>,             // Replaced txids, oldest first
+    chain_tip: Option<BlockHash>,                   // The chain tip as of the last update
     backlog_stats: (BacklogStats, Instant),
 
     // monitoring
@@ -47,6 +52,7 @@ pub struct TxOverview {
     fee: u64,
     vsize: u32,
     value: u64,
+    first_seen: u64,
 }
 
 impl Mempool {
@@ -58,6 +64,10 @@ impl Mempool {
             history: HashMap::new(),
             edges: HashMap::new(),
             recent: BoundedVecDeque::new(config.mempool_recent_txs_size),
+            first_seen: HashMap::new(),
+            replacements: HashMap::new(),
+            replacements_order: VecDeque::new(),
+            chain_tip: None,
             backlog_stats: (
                 BacklogStats::default(),
                 Instant::now() - Duration::from_secs(config.mempool_backlog_stats_ttl),
@@ -102,6 +112,16 @@ impl Mempool {
         self.edges.contains_key(outpoint)
     }
 
+    // Unix time of when the tx was first seen, or None if it is not in the mempool
+    pub fn get_first_seen(&self, txid: &Txid) -> Option<u64> {
+        self.first_seen.get(txid).copied()
+    }
+
+    // The tx that replaced the given one by spending the same inputs, if it was replaced recently
+    pub fn replaced_by(&self, txid: &Txid) -> Option<Txid> {
+        self.replacements.get(txid).copied()
+    }
+
     pub fn get_tx_fee(&self, txid: &Txid) -> Option<u64> {
         Some(self.feeinfo.get(txid)?.fee)
     }
@@ -240,6 +260,21 @@ impl Mempool {
         self.txstore.keys().collect()
     }
 
+    // Get n txids after the given txid in the mempool
+    pub fn txids_page(&self, n: usize, start: Option<Txid>) -> Vec<&Txid> {
+        let _timer = self.latency.with_label_values(&["txids"]).start_timer();
+        let start_bound = match start {
+            Some(txid) => Excluded(txid),
+            None => Unbounded,
+        };
+
+        self.txstore
+            .range((start_bound, Unbounded))
+            .take(n)
+            .map(|(txid, _)| txid)
+            .collect()
+    }
+
     // Get all txs in the mempool
     pub fn txs(&self) -> Vec<Transaction> {
         let _timer = self.latency.with_label_values(&["txs"]).start_timer();
@@ -298,9 +333,25 @@ impl Mempool {
         if to_add.len() > self.add(to_add) {
             debug!("Mempool update added less transactions than expected");
         }
+        // Keep track of the removed transactions that were replaced by new ones
+        self.track_replacements(&to_remove);
         // Remove missing transactions
         self.remove(to_remove);
 
+        // After a reorg, prevouts may have moved between the chain and the mempool
+        // (e.g. txs from orphaned blocks returning to the mempool), so re-process everything
+        let headers = self.chain.store().indexed_headers();
+        let prev_tip = self.chain_tip.replace(*headers.tip());
+        let reorged = take_txs_after_reorg(&headers, prev_tip.as_ref(), &mut self.txstore);
+        drop(headers);
+        if let Some(txs) = reorged {
+            warn!(
+                "chain tip {} was orphaned, rebuilding the mempool",
+                prev_tip.unwrap()
+            );
+            self.rebuild(txs);
+        }
+
         self.count
             .with_label_values(&["txs"])
             .set(self.txstore.len() as f64);
@@ -313,7 +364,10 @@ impl Mempool {
                 .latency
                 .with_label_values(&["update_backlog_stats"])
                 .start_timer();
-            self.backlog_stats = (BacklogStats::new(&self.feeinfo), Instant::now());
+            self.backlog_stats = (
+                BacklogStats::new(&self.feeinfo, &self.config),
+                Instant::now(),
+            );
         }
 
         Ok(())
@@ -348,12 +402,18 @@ impl Mempool {
         }
         debug!("Adding {} transactions to Mempool", txlen);
 
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs());
+
         let mut txids = Vec::with_capacity(txs.len());
         // Phase 1: add to txstore
         for tx in txs {
             let txid = tx.txid();
             txids.push(txid);
             self.txstore.insert(txid, tx);
+            // keep the original time if the tx was already seen before
+            self.first_seen.entry(txid).or_insert(now);
         }
 
         // Phase 2: index history and spend edges (some txos can be missing)
@@ -391,6 +451,7 @@ impl Mempool {
                 fee: feeinfo.fee,
                 vsize: feeinfo.vsize,
                 value: prevouts.values().map(|prevout| prevout.value).sum(),
+                first_seen: self.first_seen[&txid],
             });
 
             self.feeinfo.insert(txid, feeinfo);
@@ -447,6 +508,25 @@ impl Mempool {
         processed_count
     }
 
+    /// Drop all derived state and re-add the transactions taken out of the txstore by
+    /// take_txs_after_reorg(), looking up their prevouts again.
+    fn rebuild(&mut self, txs: Vec<Transaction>) {
+        let _timer = self.latency.with_label_values(&["rebuild"]).start_timer();
+        self.feeinfo.clear();
+        self.history.clear();
+        self.edges.clear();
+        // first_seen is kept as is, and the recent txs list isn't re-populated with old txs
+        let recent = std::mem::replace(
+            &mut self.recent,
+            BoundedVecDeque::new(self.config.mempool_recent_txs_size),
+        );
+        let count = txs.len();
+        if count > self.add(txs) {
+            debug!("Mempool rebuild processed less transactions than expected");
+        }
+        self.recent = recent;
+    }
+
     /// Returns None if the lookup fails (mempool transaction RBF-ed etc.)
     pub fn lookup_txo(&self, outpoint: &OutPoint) -> Option<TxOut> {
         let mut outpoints = BTreeSet::new();
@@ -505,6 +585,36 @@ impl Mempool {
             .collect()
     }
 
+    // Must be called after adding the new transactions but before removing the old ones,
+    // while the spend edges of the removed txs are already overwritten by their replacements.
+    fn track_replacements(&mut self, removed: &HashSet<&Txid>) {
+        let replacements: Vec<(Txid, Txid)> = removed
+            .iter()
+            .filter_map(|txid| {
+                let tx = self.txstore.get(*txid)?;
+                tx.input.iter().find_map(|txin| {
+                    let (spending_txid, _) = self.edges.get(&txin.previous_output)?;
+                    if spending_txid != *txid && !removed.contains(spending_txid) {
+                        Some((**txid, *spending_txid))
+                    } else {
+                        None
+                    }
+                })
+            })
+            .collect();
+
+        for (txid, replaced_by) in replacements {
+            debug!("mempool tx {} was replaced by {}", txid, replaced_by);
+            if self.replacements.insert(txid, replaced_by).is_none() {
+                self.replacements_order.push_back(txid);
+            }
+        }
+        while self.replacements_order.len() > RECENT_REPLACEMENTS_SIZE {
+            let txid = self.replacements_order.pop_front().unwrap();
+            self.replacements.remove(&txid);
+        }
+    }
+
     fn remove(&mut self, to_remove: HashSet<&Txid>) {
         self.delta
             .with_label_values(&["remove"])
@@ -520,6 +630,8 @@ impl Mempool {
                 warn!("missing mempool tx feeinfo {}", txid);
                 None
             });
+
+            self.first_seen.remove(*txid);
         }
 
         // TODO: make it more efficient (currently it takes O(|mempool|) time)
@@ -536,6 +648,20 @@ impl Mempool {
 
 }
 
+/// If the chain tip seen by the last update is no longer part of the best chain, take all the
+/// transactions out of `txstore` so that they can be re-added with their prevouts looked up again.
+fn take_txs_after_reorg(
+    headers: &HeaderList,
+    prev_tip: Option<&BlockHash>,
+    txstore: &mut BTreeMap<Txid, Transaction>,
+) -> Option<Vec<Transaction>> {
+    let prev_tip = prev_tip?;
+    if prev_tip == headers.tip() || headers.header_by_blockhash(prev_tip).is_some() {
+        return None;
+    }
+    Some(std::mem::take(txstore).into_values().collect())
+}
+
 #[derive(Serialize)]
 pub struct BacklogStats {
     pub count: u32,
@@ -554,7 +680,7 @@ impl BacklogStats {
         }
     }
 
-    fn new(feeinfo: &HashMap<Txid, TxFeeInfo>) -> Self {
+    fn new(feeinfo: &HashMap<Txid, TxFeeInfo>, config: &Config) -> Self {
         let (count, vsize, total_fee) = feeinfo
             .values()
             .fold((0, 0, 0), |(count, vsize, fee), feeinfo| {
@@ -565,7 +691,76 @@ impl BacklogStats {
             count,
             vsize,
             total_fee,
-            fee_histogram: make_fee_histogram(feeinfo.values().collect()),
+            fee_histogram: make_fee_histogram(
+                feeinfo.values().collect(),
+                config.fee_histogram_bin_width,
+                &config.fee_histogram_buckets,
+            ),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chain::BlockHeader;
+
+    // extend `headers` with blocks of the given times on top of `prev_blockhash`
+    fn apply_blocks(headers: &mut HeaderList, mut prev_blockhash: BlockHash, times: &[u32]) {
+        let blocks = times
+            .iter()
+            .map(|&time| {
+                let header = BlockHeader {
+                    version: 1,
+                    prev_blockhash,
+                    merkle_root: Default::default(),
+                    time,
+                    bits: 0x207fffff,
+                    nonce: 0,
+                };
+                prev_blockhash = header.block_hash();
+                header
+            })
+            .collect();
+        let entries = headers.order(blocks);
+        headers.apply(entries);
+    }
+
+    #[test]
+    fn test_take_txs_after_reorg() {
+        let tx = |lock_time| Transaction {
+            version: 1,
+            lock_time,
+            input: vec![],
+            output: vec![],
+        };
+        // a tx from the orphaned blocks returned to the mempool next to one that was there already
+        let mut txstore = BTreeMap::new();
+        for t in vec![tx(1), tx(2)] {
+            txstore.insert(t.txid(), t);
+        }
+
+        let mut headers = HeaderList::empty();
+        apply_blocks(&mut headers, BlockHash::default(), &[0, 1, 2, 3]);
+        let prev_tip = *headers.tip();
+        assert!(take_txs_after_reorg(&headers, None, &mut txstore).is_none());
+        assert!(take_txs_after_reorg(&headers, Some(&prev_tip), &mut txstore).is_none());
+
+        // extending the chain keeps the mempool state as is
+        apply_blocks(&mut headers, prev_tip, &[4]);
+        assert!(take_txs_after_reorg(&headers, Some(&prev_tip), &mut txstore).is_none());
+        assert_eq!(txstore.len(), 2);
+
+        // a 2-block reorg orphaning the previous tip hands back all txs to be re-added
+        let prev_tip = *headers.tip();
+        let fork_point = *headers.header_by_height(2).unwrap().hash();
+        apply_blocks(&mut headers, fork_point, &[13, 14, 15]);
+        assert!(headers.header_by_blockhash(&prev_tip).is_none());
+        let txs = take_txs_after_reorg(&headers, Some(&prev_tip), &mut txstore).unwrap();
+        assert_eq!(
+            txs.iter().map(Transaction::txid).collect::<HashSet<_>>(),
+            HashSet::from_iter(vec![tx(1).txid(), tx(2).txid()])
+        );
+        assert!(txstore.is_empty());
+    }
+}
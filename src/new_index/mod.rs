@@ -1,3 +1,4 @@
+mod compaction;
 pub mod db;
 mod fetch;
 mod mempool;
@@ -5,7 +6,8 @@ pub mod precache;
 mod query;
 pub mod schema;
 
-pub use self::db::{DBRow, DB};
+pub use self::compaction::IdleCompactor;
+pub use self::db::{DBFlush, DBRow, DB};
 pub use self::fetch::{BlockEntry, FetchFrom};
 pub use self::mempool::Mempool;
 pub use self::query::Query;
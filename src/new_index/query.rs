@@ -8,7 +8,7 @@ use crate::chain::{Network, OutPoint, Transaction, TxOut, Txid};
 use crate::config::Config;
 use crate::daemon::Daemon;
 use crate::errors::*;
-use crate::new_index::{ChainQuery, Mempool, ScriptStats, SpendingInput, Utxo};
+use crate::new_index::{ChainQuery, DBFlush, Mempool, ScriptStats, SpendingInput, Utxo};
 use crate::util::{is_spendable, BlockId, Bytes, TransactionStatus};
 
 
@@ -79,12 +79,10 @@ impl Query {
         Ok(txid)
     }
 
-    pub fn utxo(&self, scripthash: &[u8]) -> Result<Vec<Utxo>> {
-        let mut utxos = self.chain.utxo(
-            scripthash,
-            self.config.utxos_limit,
-            super::db::DBFlush::Enable,
-        )?;
+    pub fn utxo(&self, scripthash: &[u8], flush: DBFlush) -> Result<Vec<Utxo>> {
+        let mut utxos = self
+            .chain
+            .utxo(scripthash, self.config.utxos_limit, flush)?;
         let mempool = self.mempool();
         utxos.retain(|utxo| !mempool.has_spend(&OutPoint::from(utxo)));
         utxos.extend(mempool.utxo(scripthash));
@@ -105,11 +103,11 @@ impl Query {
         confirmed_txids.chain(mempool_txids).collect()
     }
 
-    pub fn stats(&self, scripthash: &[u8]) -> (ScriptStats, ScriptStats) {
-        (
-            self.chain.stats(scripthash, super::db::DBFlush::Enable),
+    pub fn stats(&self, scripthash: &[u8]) -> Result<(ScriptStats, ScriptStats)> {
+        Ok((
+            self.chain.stats(scripthash, DBFlush::Enable)?,
             self.mempool().stats(scripthash),
-        )
+        ))
     }
 
     pub fn lookup_txn(&self, txid: &Txid) -> Option<Transaction> {
@@ -155,7 +153,11 @@ impl Query {
     }
 
     pub fn get_tx_status(&self, txid: &Txid) -> TransactionStatus {
-        TransactionStatus::from(self.chain.tx_confirming_block(txid))
+        let mut status = TransactionStatus::from(self.chain.tx_confirming_block(txid));
+        if !status.confirmed {
+            status.replaced_by = self.mempool().replaced_by(txid);
+        }
+        status
     }
 
     pub fn get_mempool_tx_fee(&self, txid: &Txid) -> Option<u64> {
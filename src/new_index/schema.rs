@@ -1,6 +1,7 @@
 use tidecoin::hashes::sha256d::Hash as Sha256dHash;
 use tidecoin::util::merkleblock::MerkleBlock;
 use tidecoin::VarInt;
+use error_chain::ChainedError;
 use itertools::Itertools;
 use rayon::prelude::*;
 use sha2::{Digest, Sha256};
@@ -11,7 +12,8 @@ use tidecoin::consensus::encode::{deserialize, serialize};
 use std::collections::{BTreeSet, HashMap, HashSet};
 use std::convert::TryInto;
 use std::path::Path;
-use std::sync::{Arc, RwLock};
+use std::sync::{Arc, RwLock, RwLockReadGuard};
+use tokio::sync::watch;
 
 use crate::chain::{
     BlockHash, BlockHeader, Network, OutPoint, Script, Transaction, TxOut, Txid, Value,
@@ -19,7 +21,9 @@ use crate::chain::{
 use crate::config::Config;
 use crate::daemon::Daemon;
 use crate::errors::*;
-use crate::metrics::{Gauge, HistogramOpts, HistogramTimer, HistogramVec, MetricOpts, Metrics};
+use crate::metrics::{
+    Counter, Gauge, HistogramOpts, HistogramTimer, HistogramVec, MetricOpts, Metrics,
+};
 use crate::util::{
     bincode_util, full_hash, has_prevout, is_spendable, BlockHeaderMeta, BlockId, BlockMeta,
     BlockStatus, Bytes, HeaderEntry, HeaderList, ScriptToAddr,
@@ -30,6 +34,15 @@ use crate::new_index::fetch::{start_fetcher, BlockEntry, FetchFrom};
 
 
 const MIN_HISTORY_ITEMS_TO_CACHE: usize = 100;
+const HISTORY_ENCODING_SAMPLE: usize = 1000;
+// pruned entries can't be recovered if a reorg orphans their blocks, so stay clear of the tip
+const PRUNE_REORG_MARGIN: usize = 10;
+
+// Schema versions of each DB, bump the one whose row format changes so that only it gets
+// reindexed (see DB::open). All three started out at the previously shared version 1.
+const TXSTORE_DB_VERSION: u32 = 1;
+const HISTORY_DB_VERSION: u32 = 1;
+const CACHE_DB_VERSION: u32 = 1;
 
 pub struct Store {
     // TODO: should be column families
@@ -39,20 +52,34 @@ pub struct Store {
     added_blockhashes: RwLock<HashSet<BlockHash>>,
     indexed_blockhashes: RwLock<HashSet<BlockHash>>,
     indexed_headers: RwLock<HeaderList>,
+    broken_headers: Option<usize>, // height the loaded headers stopped connecting at, if any
+    tip_height: watch::Sender<usize>,
 }
 
 impl Store {
     pub fn open(path: &Path, config: &Config) -> Self {
-        let txstore_db = DB::open(&path.join("txstore"), config);
+        let reindex = config.reindex_incompatible_dbs;
+        let txstore_db = DB::open(&path.join("txstore"), TXSTORE_DB_VERSION, reindex, config);
         let added_blockhashes = load_blockhashes(&txstore_db, &BlockRow::done_filter());
         debug!("{} blocks were added", added_blockhashes.len());
 
-        let history_db = DB::open(&path.join("history"), config);
+        let history_db = DB::open(&path.join("history"), HISTORY_DB_VERSION, reindex, config);
         let indexed_blockhashes = load_blockhashes(&history_db, &BlockRow::done_filter());
         debug!("{} blocks were indexed", indexed_blockhashes.len());
 
-        let cache_db = DB::open(&path.join("cache"), config);
+        // `t` is only written once the history is indexed too, so an empty history db next to a
+        // synced txstore was wiped for a reindex. Drop the tip to have all blocks indexed again.
+        if indexed_blockhashes.is_empty() && !config.serve_only && txstore_db.get(b"t").is_some() {
+            warn!("history db is empty, reindexing the history of all blocks");
+            txstore_db.delete(vec![b"t".to_vec()]);
+        }
+
+        // the cache only holds derived data and is safe to rebuild, unless the history was
+        // pruned: it then holds the only record of the pruned entries
+        let wipe_cache = reindex || history_db.iter_scan(b"p").next().is_none();
+        let cache_db = DB::open(&path.join("cache"), CACHE_DB_VERSION, wipe_cache, config);
 
+        let mut broken_headers = None;
         let headers = if let Some(tip_hash) = txstore_db.get(b"t") {
             let tip_hash = deserialize(&tip_hash).expect("invalid chain tip in `t`");
             let headers_map = load_blockheaders(&txstore_db);
@@ -61,11 +88,22 @@ impl Store {
                 headers_map.len(),
                 tip_hash
             );
-            HeaderList::new(headers_map, tip_hash)
+            HeaderList::new(&headers_map, tip_hash).unwrap_or_else(|e| {
+                if config.serve_only {
+                    panic!("{}", e);
+                }
+                // keep the part that does connect, for Indexer::verify_headers to deal with
+                let headers = HeaderList::longest_chain(&headers_map);
+                error!("{}, headers only connect up to height {}", e, headers.len());
+                broken_headers = Some(headers.len());
+                headers
+            })
         } else {
             HeaderList::empty()
         };
 
+        let (tip_height, _) = watch::channel(headers.len().saturating_sub(1));
+
         Store {
             txstore_db,
             history_db,
@@ -73,6 +111,8 @@ impl Store {
             added_blockhashes: RwLock::new(added_blockhashes),
             indexed_blockhashes: RwLock::new(indexed_blockhashes),
             indexed_headers: RwLock::new(headers),
+            broken_headers,
+            tip_height,
         }
     }
 
@@ -88,9 +128,120 @@ impl Store {
         &self.cache_db
     }
 
+    pub fn indexed_headers(&self) -> RwLockReadGuard<HeaderList> {
+        self.indexed_headers.read().unwrap()
+    }
+
     pub fn done_initial_sync(&self) -> bool {
         self.txstore_db.get(b"t").is_some()
     }
+
+    /// Get notified when the indexed tip height changes
+    pub fn subscribe_tip_height(&self) -> watch::Receiver<usize> {
+        self.tip_height.subscribe()
+    }
+
+    /// Check that the headers loaded from the DB connected back to genesis from the `t` tip and
+    /// that all of their blocks were fully added and indexed. Returns the first bad height.
+    pub fn verify_headers(&self) -> Option<usize> {
+        let headers = self.indexed_headers.read().unwrap();
+        let added_blockhashes = self.added_blockhashes.read().unwrap();
+        let indexed_blockhashes = self.indexed_blockhashes.read().unwrap();
+
+        headers
+            .iter()
+            .position(|entry| {
+                !added_blockhashes.contains(entry.hash())
+                    || !indexed_blockhashes.contains(entry.hash())
+            })
+            .or(self.broken_headers)
+    }
+
+    /// Check a sample of the history rows against the blocks confirming their txs. History keys
+    /// must be big-endian (see bincode_util) for the height-ordered scans to work, a row written
+    /// little-endian either fails to parse or decodes to a byte-swapped height.
+    pub fn verify_history_encoding(&self) -> Result<()> {
+        let headers = self.indexed_headers.read().unwrap();
+        let sample = self
+            .history_db
+            .iter_scan(b"H")
+            .take(HISTORY_ENCODING_SAMPLE);
+        for row in sample {
+            verify_history_key(&row.key, |txid| {
+                let confs: Vec<TxConfRow> = self
+                    .txstore_db
+                    .iter_scan(&TxConfRow::filter(&txid[..]))
+                    .map(TxConfRow::from_row)
+                    .collect();
+                let heights = confs
+                    .iter()
+                    .filter_map(|conf| {
+                        headers.header_by_blockhash(&deserialize(&conf.key.blockhash).unwrap())
+                    })
+                    .map(|header| header.height())
+                    .collect();
+                (confs.len(), heights)
+            })?;
+        }
+        Ok(())
+    }
+
+    /// Roll the indexed tip back to just below `height`, so that the next indexer update
+    /// re-fetches the blocks above it from the daemon.
+    fn truncate_headers(&self, height: usize) {
+        let mut headers = self.indexed_headers.write().unwrap();
+        headers.truncate(height);
+        if headers.is_empty() {
+            self.txstore_db.delete(vec![b"t".to_vec()]);
+        } else {
+            self.txstore_db.put_sync(b"t", &serialize(headers.tip()));
+        }
+        self.tip_height
+            .send_replace(headers.len().saturating_sub(1));
+    }
+
+    /// Pick up the progress made by the indexer when running with --serve-only,
+    /// returning true if the tip has changed.
+    pub fn catch_up_with_primary(&self) -> bool {
+        self.txstore_db.catch_up();
+        self.history_db.catch_up();
+        self.cache_db.catch_up();
+
+        // `t` is only updated once both the txstore and history DBs are done with the new blocks
+        let tip_hash: BlockHash = match self.txstore_db.get(b"t") {
+            Some(tip_hash) => deserialize(&tip_hash).expect("invalid chain tip in `t`"),
+            None => return false, // initial sync is still running
+        };
+
+        let mut headers = self.indexed_headers.write().unwrap();
+        if *headers.tip() == tip_hash {
+            return false;
+        }
+
+        // walk back from the new tip until reaching a block that is part of our best chain
+        let mut new_headers = vec![];
+        let mut blockhash = tip_hash;
+        while blockhash != BlockHash::default() && headers.header_by_blockhash(&blockhash).is_none()
+        {
+            let header: BlockHeader = self
+                .txstore_db
+                .get(&BlockRow::header_key(full_hash(&blockhash[..])))
+                .map(|val| deserialize(&val).expect("failed to parse BlockHeader"))
+                .unwrap_or_else(|| panic!("missing header for indexed block {}", blockhash));
+            blockhash = header.prev_blockhash;
+            new_headers.push(header);
+        }
+        new_headers.reverse();
+
+        let new_headers = headers.order(new_headers);
+        let new_hashes = new_headers.iter().map(|h| *h.hash());
+        self.added_blockhashes.write().unwrap().extend(new_hashes.clone());
+        self.indexed_blockhashes.write().unwrap().extend(new_hashes);
+        headers.apply(new_headers);
+        self.tip_height.send_replace(headers.len() - 1);
+        debug!("caught up with primary, tip at {:?}", tip_hash);
+        true
+    }
 }
 
 type UtxoMap = HashMap<OutPoint, (BlockId, Value)>;
@@ -151,6 +302,10 @@ pub struct Indexer {
     iconfig: IndexerConfig,
     duration: HistogramVec,
     tip_metric: Gauge,
+    orphaned_metric: Counter,
+    reorg_height_metric: Gauge,
+    reorg_depth_metric: Gauge,
+    headers_consistent_metric: Gauge,
 }
 
 struct IndexerConfig {
@@ -173,9 +328,12 @@ impl From<&Config> for IndexerConfig {
 
 pub struct ChainQuery {
     store: Arc<Store>, // TODO: should be used as read-only
-    daemon: Arc<Daemon>,
+    // only used in light mode, None in tests
+    daemon: Option<Arc<Daemon>>,
     light_mode: bool,
     duration: HistogramVec,
+    decode_failures: Counter,
+    insane_stats_caches: Counter,
     network: Network,
 }
 
@@ -192,9 +350,52 @@ impl Indexer {
                 &["step"],
             ),
             tip_metric: metrics.gauge(MetricOpts::new("tip_height", "Current chain tip height")),
+            orphaned_metric: metrics.counter(MetricOpts::new(
+                "orphaned_blocks",
+                "# of blocks removed from the best chain by reorgs",
+            )),
+            reorg_height_metric: metrics.gauge(MetricOpts::new(
+                "last_reorg_height",
+                "Height of the first block replaced by the last reorg",
+            )),
+            reorg_depth_metric: metrics.gauge(MetricOpts::new(
+                "last_reorg_depth",
+                "# of blocks orphaned by the last reorg",
+            )),
+            headers_consistent_metric: metrics.gauge(MetricOpts::new(
+                "indexed_headers_consistent",
+                "Whether the indexed headers passed the startup verification (1) or not (0)",
+            )),
         }
     }
 
+    /// Verify the headers loaded from the DB. With `repair`, an inconsistent chain is rolled
+    /// back to its last good block and the rest gets re-fetched from the daemon by update().
+    pub fn verify_headers(&self, repair: bool) -> Result<()> {
+        let _timer = self.start_timer("verify_headers");
+        let bad_height = match self.store.verify_headers() {
+            None => {
+                info!("indexed headers verified");
+                self.headers_consistent_metric.set(1);
+                return Ok(());
+            }
+            Some(height) => height,
+        };
+        self.headers_consistent_metric.set(0);
+        if !repair {
+            bail!(
+                "indexed headers are inconsistent at height {}, run with --repair-headers to re-fetch them from the daemon",
+                bad_height
+            );
+        }
+        warn!(
+            "indexed headers are inconsistent at height {}, re-fetching from the daemon",
+            bad_height
+        );
+        self.store.truncate_headers(bad_height);
+        Ok(())
+    }
+
     fn start_timer(&self, name: &str) -> HistogramTimer {
         self.duration.with_label_values(&[name]).start_timer()
     }
@@ -273,14 +474,27 @@ impl Indexer {
         self.store.txstore_db.put_sync(b"t", &serialize(&tip));
 
         let mut headers = self.store.indexed_headers.write().unwrap();
-        headers.apply(new_headers);
+        let removed = headers.apply(new_headers);
         assert_eq!(tip, *headers.tip());
 
+        if let Some(fork) = removed.first() {
+            warn!(
+                "reorg at height {}, {} blocks orphaned: {:?}",
+                fork.height(),
+                removed.len(),
+                removed
+            );
+            self.orphaned_metric.inc_by(removed.len() as u64);
+            self.reorg_height_metric.set(fork.height() as i64);
+            self.reorg_depth_metric.set(removed.len() as i64);
+        }
+
         if let FetchFrom::BlkFiles = self.from {
             self.from = FetchFrom::Bitcoind;
         }
 
         self.tip_metric.set(headers.len() as i64 - 1);
+        self.store.tip_height.send_replace(headers.len() - 1);
 
         Ok(tip)
     }
@@ -308,7 +522,12 @@ impl Indexer {
         debug!("Indexing {} blocks with Indexer", blocks.len());
         let previous_txos_map = {
             let _timer = self.start_timer("index_lookup");
-            lookup_txos(&self.store.txstore_db, &get_previous_txos(blocks), false)
+            lookup_txos(
+                &self.store.txstore_db,
+                &get_previous_txos(blocks),
+                false,
+                None,
+            )
         };
         let rows = {
             let _timer = self.start_timer("index_process");
@@ -330,13 +549,21 @@ impl ChainQuery {
     pub fn new(store: Arc<Store>, daemon: Arc<Daemon>, config: &Config, metrics: &Metrics) -> Self {
         ChainQuery {
             store,
-            daemon,
+            daemon: Some(daemon),
             light_mode: config.light_mode,
             network: config.network_type,
             duration: metrics.histogram_vec(
                 HistogramOpts::new("query_duration", "Index query duration (in seconds)"),
                 &["name"],
             ),
+            decode_failures: metrics.counter(MetricOpts::new(
+                "query_tx_decode_failures",
+                "# of stored transactions and outputs that failed to decode",
+            )),
+            insane_stats_caches: metrics.counter(MetricOpts::new(
+                "query_insane_stats_caches",
+                "# of cached address stats dropped for failing the sanity check",
+            )),
         }
     }
 
@@ -352,13 +579,16 @@ impl ChainQuery {
         self.duration.with_label_values(&[name]).start_timer()
     }
 
+    fn daemon(&self) -> &Daemon {
+        self.daemon.as_ref().expect("light mode requires a daemon")
+    }
+
     pub fn get_block_txids(&self, hash: &BlockHash) -> Option<Vec<Txid>> {
         let _timer = self.start_timer("get_block_txids");
 
         if self.light_mode {
             // TODO fetch block as binary from REST API instead of as hex
-            let mut blockinfo = self.daemon.getblock_raw(hash, 1).ok()?;
-            Some(serde_json::from_value(blockinfo["tx"].take()).unwrap())
+            light_reply(hash, parse_block_txids(self.daemon().getblock_raw(hash, 1)))
         } else {
             self.store
                 .txstore_db
@@ -374,8 +604,7 @@ impl ChainQuery {
 
         let txids: Option<Vec<Txid>> = if self.light_mode {
             // TODO fetch block as binary from REST API instead of as hex
-            let mut blockinfo = self.daemon.getblock_raw(hash, 1).ok()?;
-            Some(serde_json::from_value(blockinfo["tx"].take()).unwrap())
+            light_reply(hash, parse_block_txids(self.daemon().getblock_raw(hash, 1)))
         } else {
             self.store
                 .txstore_db
@@ -403,8 +632,10 @@ impl ChainQuery {
         let _timer = self.start_timer("get_block_meta");
 
         if self.light_mode {
-            let blockinfo = self.daemon.getblock_raw(hash, 1).ok()?;
-            Some(serde_json::from_value(blockinfo).unwrap())
+            let meta = self.daemon().getblock_raw(hash, 1).and_then(|blockinfo| {
+                serde_json::from_value(blockinfo).chain_err(|| "invalid block meta")
+            });
+            light_reply(hash, meta)
         } else {
             self.store
                 .txstore_db
@@ -419,8 +650,7 @@ impl ChainQuery {
         let _timer = self.start_timer("get_block_raw");
 
         if self.light_mode {
-            let blockhex = self.daemon.getblock_raw(hash, 0).ok()?;
-            Some(hex::decode(blockhex.as_str().unwrap()).unwrap())
+            light_reply(hash, parse_hex_reply(self.daemon().getblock_raw(hash, 0)))
         } else {
             let entry = self.header_by_hash(hash)?;
             let meta = self.get_block_meta(hash)?;
@@ -448,7 +678,7 @@ impl ChainQuery {
         Some(self.header_by_hash(hash)?.header().clone())
     }
 
-    pub fn get_mtp(&self, height: usize) -> u32 {
+    pub fn get_mtp(&self, height: usize) -> Option<u32> {
         let _timer = self.start_timer("get_block_mtp");
         self.store.indexed_headers.read().unwrap().get_mtp(height)
     }
@@ -458,7 +688,7 @@ impl ChainQuery {
         let header_entry = self.header_by_hash(hash)?;
         Some(BlockHeaderMeta {
             meta: self.get_block_meta(hash)?,
-            mtp: self.get_mtp(header_entry.height()),
+            mtp: self.get_mtp(header_entry.height())?,
             header_entry,
         })
     }
@@ -541,28 +771,33 @@ impl ChainQuery {
             .collect()
     }
 
+    /// Get the earliest confirmed tx funding the scripthash. Scans the history forward and stops
+    /// at the first funding row that is part of the best chain.
+    pub fn get_first_use(&self, scripthash: &[u8]) -> Option<(Txid, BlockId)> {
+        let _timer = self.start_timer("get_first_use");
+        self.history_iter_scan(b'H', scripthash, 0)
+            .map(TxHistoryRow::from_row)
+            .filter(|row| matches!(row.key.txinfo, TxHistoryInfo::Funding(_)))
+            .map(|row| row.get_txid())
+            .find_map(|txid| self.tx_confirming_block(&txid).map(|b| (txid, b)))
+    }
+
     // TODO: avoid duplication with stats/stats_delta?
     pub fn utxo(&self, scripthash: &[u8], limit: usize, flush: DBFlush) -> Result<Vec<Utxo>> {
         let _timer = self.start_timer("utxo");
 
-        // get the last known utxo set and the blockhash it was updated for.
-        // invalidates the cache if the block was orphaned.
-        let cache: Option<(UtxoMap, usize)> = self
-            .store
-            .cache_db
-            .get(&UtxoCacheRow::key(scripthash))
-            .map(|c| bincode_util::deserialize_little(&c).unwrap())
-            .and_then(|(utxos_cache, blockhash)| {
-                self.height_by_hash(&blockhash)
-                    .map(|height| (utxos_cache, height))
-            })
-            .map(|(utxos_cache, height)| (from_utxo_cache(utxos_cache, self), height));
+        let cache = self.utxo_cache(scripthash);
         let had_cache = cache.is_some();
+        if !had_cache {
+            self.ensure_unpruned(scripthash)?;
+        }
 
         // update utxo set with new transactions since
         let (newutxos, lastblock, processed_items) = cache.map_or_else(
-            || self.utxo_delta(scripthash, HashMap::new(), 0, limit),
-            |(oldutxos, blockheight)| self.utxo_delta(scripthash, oldutxos, blockheight + 1, limit),
+            || self.utxo_delta(scripthash, HashMap::new(), 0, usize::MAX, limit),
+            |(oldutxos, blockheight)| {
+                self.utxo_delta(scripthash, oldutxos, blockheight + 1, usize::MAX, limit)
+            },
         )?;
 
         // save updated utxo set to cache
@@ -602,17 +837,34 @@ impl ChainQuery {
             .collect())
     }
 
+    // get the last known utxo set and the height of the block it was updated for.
+    // invalidates the cache if the block was orphaned.
+    fn utxo_cache(&self, scripthash: &[u8]) -> Option<(UtxoMap, usize)> {
+        self.store
+            .cache_db
+            .get(&UtxoCacheRow::key(scripthash))
+            .map(|c| bincode_util::deserialize_little(&c).unwrap())
+            .and_then(|(utxos_cache, blockhash)| {
+                self.height_by_hash(&blockhash)
+                    .map(|height| (utxos_cache, height))
+            })
+            .and_then(|(utxos_cache, height)| Some((from_utxo_cache(utxos_cache, self)?, height)))
+    }
+
+    // apply the history entries confirmed at heights start_height..end_height
     fn utxo_delta(
         &self,
         scripthash: &[u8],
         init_utxos: UtxoMap,
         start_height: usize,
+        end_height: usize,
         limit: usize,
     ) -> Result<(UtxoMap, Option<BlockHash>, usize)> {
         let _timer = self.start_timer("utxo_delta");
         let history_iter = self
             .history_iter_scan(b'H', scripthash, start_height)
             .map(TxHistoryRow::from_row)
+            .take_while(|history| (history.key.confirmed_height as usize) < end_height)
             .filter_map(|history| {
                 self.tx_confirming_block(&history.get_txid())
                     // drop history entries that were previously confirmed in a re-orged block and later
@@ -650,31 +902,30 @@ impl ChainQuery {
         Ok((utxos, lastblock, processed_items))
     }
 
-    pub fn stats(&self, scripthash: &[u8], flush: DBFlush) -> ScriptStats {
+    pub fn stats(&self, scripthash: &[u8], flush: DBFlush) -> Result<ScriptStats> {
         let _timer = self.start_timer("stats");
 
-        // get the last known stats and the blockhash they are updated for.
-        // invalidates the cache if the block was orphaned or if values are out of sync.
-        let cache: Option<(ScriptStats, usize)> = self
-            .store
-            .cache_db
-            .get(&StatsCacheRow::key(scripthash))
-            .map(|c| bincode_util::deserialize_little::<(ScriptStats, BlockHash)>(&c).unwrap())
-            // Check that the values are sane (No negative balances or balances with 0 utxos)
-            .filter(|(stats, _)| stats.is_sane())
-            .and_then(|(stats, blockhash)| {
-                self.height_by_hash(&blockhash)
-                    .map(|height| (stats, height))
-            });
+        let cache = self.stats_cache(scripthash);
+        if cache.is_none() {
+            self.ensure_unpruned(scripthash)?;
+        }
 
         // update stats with new transactions since
         let (newstats, lastblock) = cache.map_or_else(
-            || self.stats_delta(scripthash, ScriptStats::default(), 0),
-            |(oldstats, blockheight)| self.stats_delta(scripthash, oldstats, blockheight + 1),
+            || self.stats_delta(scripthash, ScriptStats::default(), 0, usize::MAX),
+            |(oldstats, blockheight)| {
+                self.stats_delta(scripthash, oldstats, blockheight + 1, usize::MAX)
+            },
         );
 
-        // save updated stats to cache
-        if let Some(lastblock) = lastblock {
+        // save updated stats to cache, unless they're broken and would just get dropped again
+        if !newstats.is_sane() {
+            error!(
+                "computed insane stats for scripthash {}: {:?}",
+                hex::encode(scripthash),
+                newstats
+            );
+        } else if let Some(lastblock) = lastblock {
             if newstats.funded_txo_count + newstats.spent_txo_count > MIN_HISTORY_ITEMS_TO_CACHE {
                 self.store.cache_db.write(
                     vec![StatsCacheRow::new(scripthash, &newstats, &lastblock).into_row()],
@@ -683,19 +934,70 @@ impl ChainQuery {
             }
         }
 
-        newstats
+        Ok(newstats)
     }
 
+    // the height below which the history of the script was pruned, if it was
+    fn pruned_height(&self, scripthash: &[u8]) -> Option<u32> {
+        self.store
+            .history_db
+            .get(&PrunedRow::key(scripthash))
+            .map(|h| bincode_util::deserialize_little(&h).expect("invalid prune height"))
+    }
+
+    // recomputing from the history of a pruned script would silently miss the pruned entries
+    fn ensure_unpruned(&self, scripthash: &[u8]) -> Result<()> {
+        if let Some(height) = self.pruned_height(scripthash) {
+            bail!(
+                "the history of scripthash {} was pruned below height {} and its cache is no longer valid, a reindex is required",
+                hex::encode(scripthash),
+                height
+            );
+        }
+        Ok(())
+    }
+
+    // get the last known stats and the height of the block they are updated for.
+    // invalidates the cache if the block was orphaned or if values are out of sync.
+    fn stats_cache(&self, scripthash: &[u8]) -> Option<(ScriptStats, usize)> {
+        let key = StatsCacheRow::key(scripthash);
+        let (stats, blockhash) =
+            self.store.cache_db.get(&key).map(|c| {
+                bincode_util::deserialize_little::<(ScriptStats, BlockHash)>(&c).unwrap()
+            })?;
+
+        // Check that the values are sane (No negative balances or balances with 0 utxos).
+        // Insane rows are deleted so that they get rebuilt once, rather than on every request.
+        if !stats.is_sane() {
+            warn!(
+                "dropping insane stats cache for scripthash {}: {:?}",
+                hex::encode(scripthash),
+                stats
+            );
+            self.insane_stats_caches.inc();
+            // for pruned scripts, the cache is the only record of the pruned history
+            if self.pruned_height(scripthash).is_none() {
+                self.store.cache_db.delete(vec![key]);
+            }
+            return None;
+        }
+        self.height_by_hash(&blockhash)
+            .map(|height| (stats, height))
+    }
+
+    // apply the history entries confirmed at heights start_height..end_height
     fn stats_delta(
         &self,
         scripthash: &[u8],
         init_stats: ScriptStats,
         start_height: usize,
+        end_height: usize,
     ) -> (ScriptStats, Option<BlockHash>) {
         let _timer = self.start_timer("stats_delta"); // TODO: measure also the number of txns processed.
         let history_iter = self
             .history_iter_scan(b'H', scripthash, start_height)
             .map(TxHistoryRow::from_row)
+            .take_while(|history| (history.key.confirmed_height as usize) < end_height)
             .filter_map(|history| {
                 self.tx_confirming_block(&history.get_txid())
                     // drop history entries that were previously confirmed in a re-orged block and later
@@ -753,6 +1055,80 @@ impl ChainQuery {
         (stats, lastblock)
     }
 
+    /// Delete the script history and spending edges confirmed below `height`, keeping the txstore.
+    /// The stats and utxo caches of the affected scripts are updated to cover the deleted entries
+    /// first, so balances and utxos remain correct. Returns the number of pruned scripts.
+    pub fn prune_history(&self, height: usize) -> Result<usize> {
+        let _timer = self.start_timer("prune_history");
+        ensure!(height > 0, "invalid prune height {}", height);
+        ensure!(
+            height + PRUNE_REORG_MARGIN <= self.best_height(),
+            "prune height {} must be at least {} blocks below the tip at {}",
+            height,
+            PRUNE_REORG_MARGIN,
+            self.best_height()
+        );
+
+        let mut pruned_scripts = 0;
+        let mut markers = vec![];
+        let mut to_delete = vec![];
+        let rows = self
+            .store
+            .history_db
+            .iter_scan(b"H")
+            .map(TxHistoryRow::from_row)
+            .filter(|history| (history.key.confirmed_height as usize) < height)
+            .group_by(|history| history.key.hash);
+        for (scripthash, history) in &rows {
+            // bring the caches up to the prune height, they're the only record of the pruned entries
+            let cache = self.utxo_cache(&scripthash);
+            if cache.is_none() {
+                // pruning again can't rebuild caches lost since the last prune
+                self.ensure_unpruned(&scripthash)?;
+            }
+            if cache.as_ref().map_or(true, |(_, h)| h + 1 < height) {
+                let (utxos, start_height) = cache.map_or((HashMap::new(), 0), |(u, h)| (u, h + 1));
+                let (utxos, lastblock, _) =
+                    self.utxo_delta(&scripthash, utxos, start_height, height, usize::MAX)?;
+                if let Some(lastblock) = lastblock {
+                    self.store.cache_db.write(
+                        vec![UtxoCacheRow::new(&scripthash, &utxos, &lastblock).into_row()],
+                        DBFlush::Enable,
+                    );
+                }
+            }
+            let cache = self.stats_cache(&scripthash);
+            if cache.is_none() {
+                self.ensure_unpruned(&scripthash)?;
+            }
+            if cache.as_ref().map_or(true, |(_, h)| h + 1 < height) {
+                let (stats, start_height) =
+                    cache.map_or((ScriptStats::default(), 0), |(s, h)| (s, h + 1));
+                let (stats, lastblock) = self.stats_delta(&scripthash, stats, start_height, height);
+                if let Some(lastblock) = lastblock {
+                    self.store.cache_db.write(
+                        vec![StatsCacheRow::new(&scripthash, &stats, &lastblock).into_row()],
+                        DBFlush::Enable,
+                    );
+                }
+            }
+
+            markers.push(PrunedRow::new(&scripthash, height as u32));
+            to_delete.extend(history.flat_map(|history| history.prune_keys()));
+            if to_delete.len() > 100_000 {
+                // the markers must be in place before any of the entries are gone
+                self.store
+                    .history_db
+                    .write(std::mem::take(&mut markers), DBFlush::Enable);
+                self.store.history_db.delete(std::mem::take(&mut to_delete));
+            }
+            pruned_scripts += 1;
+        }
+        self.store.history_db.write(markers, DBFlush::Enable);
+        self.store.history_db.delete(to_delete);
+        Ok(pruned_scripts)
+    }
+
     pub fn address_search(&self, prefix: &str, limit: usize) -> Vec<String> {
         let _timer_scan = self.start_timer("address_search");
         self.store
@@ -791,6 +1167,17 @@ impl ChainQuery {
             .cloned()
     }
 
+    /// Get up to `count` consecutive headers starting at `start_height`, stopping at the tip
+    pub fn headers_by_height(&self, start_height: usize, count: usize) -> Vec<HeaderEntry> {
+        let headers = self.store.indexed_headers.read().unwrap();
+        headers
+            .iter()
+            .skip(start_height)
+            .take(count)
+            .cloned()
+            .collect()
+    }
+
     pub fn hash_by_height(&self, height: usize) -> Option<BlockHash> {
         self.store
             .indexed_headers
@@ -850,11 +1237,21 @@ impl ChainQuery {
 
     pub fn lookup_txn(&self, txid: &Txid, blockhash: Option<&BlockHash>) -> Option<Transaction> {
         let _timer = self.start_timer("lookup_txn");
-        self.lookup_raw_txn(txid, blockhash).map(|rawtx| {
-            let txn: Transaction = deserialize(&rawtx).expect("failed to parse Transaction");
-            assert_eq!(*txid, txn.txid());
-            txn
-        })
+        let rawtx = self.lookup_raw_txn(txid, blockhash)?;
+        // a corrupt entry is treated as missing rather than taking down the server
+        match deserialize::<Transaction>(&rawtx) {
+            Ok(txn) if txn.txid() == *txid => Some(txn),
+            Ok(txn) => {
+                self.decode_failures.inc();
+                error!("tx {} decoded with a different txid {}", txid, txn.txid());
+                None
+            }
+            Err(e) => {
+                self.decode_failures.inc();
+                error!("failed to parse tx {}: {}", txid, e);
+                None
+            }
+        }
     }
 
     pub fn lookup_raw_txn(&self, txid: &Txid, blockhash: Option<&BlockHash>) -> Option<Bytes> {
@@ -865,11 +1262,8 @@ impl ChainQuery {
                 blockhash.map_or_else(|| self.tx_confirming_block(txid).map(|b| b.hash), |_| None);
             let blockhash = blockhash.or(queried_blockhash.as_ref())?;
             // TODO fetch transaction as binary from REST API instead of as hex
-            let txhex = self
-                .daemon
-                .gettransaction_raw(txid, blockhash, false)
-                .ok()?;
-            Some(hex::decode(txhex.as_str().unwrap()).unwrap())
+            let txhex = self.daemon().gettransaction_raw(txid, blockhash, false);
+            light_reply(txid, parse_hex_reply(txhex))
         } else {
             self.store.txstore_db.get(&TxRow::key(&txid[..]))
         }
@@ -877,17 +1271,31 @@ impl ChainQuery {
 
     pub fn lookup_txo(&self, outpoint: &OutPoint) -> Option<TxOut> {
         let _timer = self.start_timer("lookup_txo");
-        lookup_txo(&self.store.txstore_db, outpoint)
+        lookup_txo(
+            &self.store.txstore_db,
+            outpoint,
+            Some(&self.decode_failures),
+        )
     }
 
     pub fn lookup_txos(&self, outpoints: &BTreeSet<OutPoint>) -> HashMap<OutPoint, TxOut> {
         let _timer = self.start_timer("lookup_txos");
-        lookup_txos(&self.store.txstore_db, outpoints, false)
+        lookup_txos(
+            &self.store.txstore_db,
+            outpoints,
+            false,
+            Some(&self.decode_failures),
+        )
     }
 
     pub fn lookup_avail_txos(&self, outpoints: &BTreeSet<OutPoint>) -> HashMap<OutPoint, TxOut> {
         let _timer = self.start_timer("lookup_available_txos");
-        lookup_txos(&self.store.txstore_db, outpoints, true)
+        lookup_txos(
+            &self.store.txstore_db,
+            outpoints,
+            true,
+            Some(&self.decode_failures),
+        )
     }
 
     pub fn lookup_spend(&self, outpoint: &OutPoint) -> Option<SpendingInput> {
@@ -917,28 +1325,31 @@ impl ChainQuery {
             .filter_map(|conf| {
                 headers.header_by_blockhash(&deserialize(&conf.key.blockhash).unwrap())
             })
-            .next()
+            // a txid confirmed more than once (see TxConfRow) resolves to its latest confirmation
+            .max_by_key(|header| header.height())
             .map(BlockId::from)
     }
 
-    pub fn get_block_status(&self, hash: &BlockHash) -> BlockStatus {
-        // TODO differentiate orphaned and non-existing blocks? telling them apart requires
-        // an additional db read.
-
+    // returns None for blocks that were never added to the store
+    pub fn get_block_status(&self, hash: &BlockHash) -> Option<BlockStatus> {
         let headers = self.store.indexed_headers.read().unwrap();
 
         // header_by_blockhash only returns blocks that are part of the best chain,
         // or None for orphaned blocks.
-        headers
-            .header_by_blockhash(hash)
-            .map_or_else(BlockStatus::orphaned, |header| {
-                BlockStatus::confirmed(
-                    header.height(),
-                    headers
-                        .header_by_height(header.height() + 1)
-                        .map(|h| *h.hash()),
-                )
-            })
+        match headers.header_by_blockhash(hash) {
+            Some(header) => Some(BlockStatus::confirmed(
+                header.height(),
+                headers
+                    .header_by_height(header.height() + 1)
+                    .map(|h| *h.hash()),
+            )),
+            // blocks we have added but that are no longer part of the best chain are orphaned,
+            // telling them apart from non-existing blocks only requires an in-memory lookup.
+            None if self.store.added_blockhashes.read().unwrap().contains(hash) => {
+                Some(BlockStatus::orphaned())
+            }
+            None => None,
+        }
     }
 
     #[cfg(not(feature = "liquid"))]
@@ -1054,10 +1465,61 @@ fn get_previous_txos(block_entries: &[BlockEntry]) -> BTreeSet<OutPoint> {
         .collect()
 }
 
+
+/// Check that a history row key decodes as big-endian to a height its tx is confirmed at.
+/// `confirmations` returns the number of confirmation rows of a tx and the heights of the
+/// ones in the best chain.
+fn verify_history_key<F>(key: &[u8], confirmations: F) -> Result<()>
+where
+    F: Fn(&Txid) -> (usize, Vec<usize>),
+{
+    let key: TxHistoryKey = bincode_util::deserialize_big(key).chain_err(|| {
+        format!(
+            "failed to parse history row {}, is the history db big-endian encoded?",
+            hex::encode(key)
+        )
+    })?;
+    let txid = key.txinfo.get_txid();
+    let (confs, heights) = confirmations(&txid);
+    // rows of txs that were (also) confirmed in orphaned blocks or above the indexed tip may
+    // be left over from another height, so only txs fully confirmed in the best chain are checked
+    if heights.is_empty() || confs > heights.len() {
+        return Ok(());
+    }
+    ensure!(
+        heights.contains(&(key.confirmed_height as usize)),
+        "history row of tx {} has height {} but the tx is confirmed at {:?}, is the history db big-endian encoded?",
+        txid,
+        key.confirmed_height,
+        heights
+    );
+    Ok(())
+}
+
+// In light mode the daemon may no longer have the requested data (e.g. a pruned block), or
+// reply with something we can't parse. Report these as missing instead of panicking.
+fn light_reply<T>(id: &dyn std::fmt::Display, reply: Result<T>) -> Option<T> {
+    reply
+        .map_err(|e| warn!("failed fetching {} from daemon: {}", id, e.display_chain()))
+        .ok()
+}
+
+fn parse_block_txids(blockinfo: Result<serde_json::Value>) -> Result<Vec<Txid>> {
+    let mut blockinfo = blockinfo?;
+    serde_json::from_value(blockinfo["tx"].take()).chain_err(|| "invalid block txids")
+}
+
+fn parse_hex_reply(reply: Result<serde_json::Value>) -> Result<Vec<u8>> {
+    let reply = reply?;
+    let hex = reply.as_str().chain_err(|| "non-string hex reply")?;
+    hex::decode(hex).chain_err(|| "invalid hex reply")
+}
+
 fn lookup_txos(
     txstore_db: &DB,
     outpoints: &BTreeSet<OutPoint>,
     allow_missing: bool,
+    decode_failures: Option<&Counter>,
 ) -> HashMap<OutPoint, TxOut> {
     let pool = rayon::ThreadPoolBuilder::new()
         .num_threads(16) // we need to saturate SSD IOPS
@@ -1068,7 +1530,7 @@ fn lookup_txos(
         outpoints
             .par_iter()
             .filter_map(|outpoint| {
-                lookup_txo(txstore_db, outpoint)
+                lookup_txo(txstore_db, outpoint, decode_failures)
                     .or_else(|| {
                         if !allow_missing {
                             panic!("missing txo {} in {:?}", outpoint, txstore_db);
@@ -1081,10 +1543,23 @@ fn lookup_txos(
     })
 }
 
-fn lookup_txo(txstore_db: &DB, outpoint: &OutPoint) -> Option<TxOut> {
-    txstore_db
-        .get(&TxOutRow::key(outpoint))
-        .map(|val| deserialize(&val).expect("failed to parse TxOut"))
+fn lookup_txo(
+    txstore_db: &DB,
+    outpoint: &OutPoint,
+    decode_failures: Option<&Counter>,
+) -> Option<TxOut> {
+    let val = txstore_db.get(&TxOutRow::key(outpoint))?;
+    // a corrupt entry is treated as missing rather than taking down the server
+    match deserialize(&val) {
+        Ok(txo) => Some(txo),
+        Err(e) => {
+            if let Some(decode_failures) = decode_failures {
+                decode_failures.inc();
+            }
+            error!("failed to parse txo {}: {}", outpoint, e);
+            None
+        }
+    }
 }
 
 fn index_blocks(
@@ -1248,6 +1723,10 @@ struct TxConfKey {
     blockhash: FullHash,
 }
 
+// Keyed by txid and blockhash, so a txid that gets confirmed in more than one block gets a row
+// per block. On chains without BIP34 (e.g. Bitcoin's duplicate coinbases at heights 91842 and
+// 91880) two identical coinbase txs can share a txid. Their TxRow is simply overwritten with the
+// same raw tx, and the outputs of the earlier one are shadowed by the later, matching bitcoind.
 struct TxConfRow {
     key: TxConfKey,
 }
@@ -1369,6 +1848,10 @@ impl BlockRow {
         b"B".to_vec()
     }
 
+    fn header_key(hash: FullHash) -> Bytes {
+        [b"B", &hash[..]].concat()
+    }
+
     fn txids_key(hash: FullHash) -> Bytes {
         [b"X", &hash[..]].concat()
     }
@@ -1501,6 +1984,22 @@ impl TxHistoryRow {
     fn get_funded_outpoint(&self) -> OutPoint {
         self.key.txinfo.get_funded_outpoint()
     }
+
+    // the keys to delete when pruning this entry: the history row itself and,
+    // for spending entries, the matching spend edge
+    fn prune_keys(self) -> Vec<Bytes> {
+        let edge = match self.key.txinfo {
+            TxHistoryInfo::Spending(ref info) => Some(
+                TxEdgeRow::new(info.prev_txid, info.prev_vout, info.txid, info.vin)
+                    .into_row()
+                    .key,
+            ),
+            _ => None,
+        };
+        let mut keys = vec![self.into_row().key];
+        keys.extend(edge);
+        keys
+    }
 }
 
 impl TxHistoryInfo {
@@ -1610,6 +2109,22 @@ impl StatsCacheRow {
     }
 }
 
+// Marks scripts whose history was pruned below the given height, see ChainQuery::prune_history
+struct PrunedRow;
+
+impl PrunedRow {
+    fn new(scripthash: &[u8], height: u32) -> DBRow {
+        DBRow {
+            key: PrunedRow::key(scripthash),
+            value: bincode_util::serialize_little(&height).unwrap(),
+        }
+    }
+
+    fn key(scripthash: &[u8]) -> Bytes {
+        [b"p", scripthash].concat()
+    }
+}
+
 type CachedUtxoMap = HashMap<(Txid, u32), (u32, Value)>; // (txid,vout) => (block_height,output_value)
 
 struct UtxoCacheRow {
@@ -1656,15 +2171,301 @@ fn make_utxo_cache(utxos: &UtxoMap) -> CachedUtxoMap {
         .collect()
 }
 
-fn from_utxo_cache(utxos_cache: CachedUtxoMap, chain: &ChainQuery) -> UtxoMap {
+// returns None if an entry points beyond the current tip (i.e. the chain was reorged
+// out from under the cache), so that the cache gets treated as invalid
+fn from_utxo_cache(utxos_cache: CachedUtxoMap, chain: &ChainQuery) -> Option<UtxoMap> {
     utxos_cache
         .into_iter()
         .map(|((txid, vout), (height, value))| {
             let outpoint = OutPoint { txid, vout };
-            let blockid = chain
-                .blockid_by_height(height as usize)
-                .expect("missing blockheader for valid utxo cache entry");
-            (outpoint, (blockid, value))
+            let blockid = chain.blockid_by_height(height as usize)?;
+            Some((outpoint, (blockid, value)))
         })
         .collect()
 }
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    #[test]
+    fn test_light_mode_pruned_block() {
+        let pruned = || -> Result<serde_json::Value> {
+            bail!("daemon returned error: Block not available (pruned data)")
+        };
+        assert!(parse_block_txids(pruned()).is_err());
+        assert!(parse_hex_reply(pruned()).is_err());
+        assert_eq!(light_reply(&"block", parse_block_txids(pruned())), None);
+
+        // malformed replies are reported as missing too
+        assert!(parse_block_txids(Ok(json!({ "tx": [1, 2] }))).is_err());
+        assert!(parse_hex_reply(Ok(json!(null))).is_err());
+        assert!(parse_hex_reply(Ok(json!("zz"))).is_err());
+
+        assert_eq!(parse_hex_reply(Ok(json!("00ff"))).unwrap(), vec![0x00, 0xff]);
+        let txid = "8ce9d58d8b0ecf5a9ce1ba1e4bcb13e7ab1ba84e0b3fcc8a7cd9ffa8d6d7a1ec";
+        assert_eq!(
+            parse_block_txids(Ok(json!({ "tx": [txid] }))).unwrap(),
+            vec![txid.parse::<Txid>().unwrap()]
+        );
+    }
+
+    #[test]
+    fn test_prune_keys() {
+        let prev_txid: Txid = "8ce9d58d8b0ecf5a9ce1ba1e4bcb13e7ab1ba84e0b3fcc8a7cd9ffa8d6d7a1ec"
+            .parse()
+            .unwrap();
+        let spending = TxHistoryRow::new(
+            &Script::new(),
+            100,
+            TxHistoryInfo::Spending(SpendingInfo {
+                txid: [1; 32],
+                vin: 3,
+                prev_txid: full_hash(&prev_txid[..]),
+                prev_vout: 7,
+                value: 1000,
+            }),
+        );
+        let history_key = bincode_util::serialize_big(&spending.key).unwrap();
+        let keys = spending.prune_keys();
+        assert_eq!(keys.len(), 2);
+        assert_eq!(keys[0], history_key);
+        // the edge must be the one lookup_spend() finds for the spent output
+        let outpoint = OutPoint {
+            txid: prev_txid,
+            vout: 7,
+        };
+        assert!(keys[1].starts_with(&TxEdgeRow::filter(&outpoint)));
+
+        let funding = TxHistoryRow::new(
+            &Script::new(),
+            100,
+            TxHistoryInfo::Funding(FundingInfo {
+                txid: [1; 32],
+                vout: 0,
+                value: 1000,
+            }),
+        );
+        assert_eq!(funding.prune_keys().len(), 1);
+    }
+
+    #[test]
+    fn test_verify_history_key() {
+        let funding = TxHistoryRow::new(
+            &Script::new(),
+            100,
+            TxHistoryInfo::Funding(FundingInfo {
+                txid: [1; 32],
+                vout: 0,
+                value: 1000,
+            }),
+        );
+        let spending = TxHistoryRow::new(
+            &Script::new(),
+            100,
+            TxHistoryInfo::Spending(SpendingInfo {
+                txid: [1; 32],
+                vin: 3,
+                prev_txid: [2; 32],
+                prev_vout: 7,
+                value: 1000,
+            }),
+        );
+        let confirmed = |_: &Txid| (1, vec![100]);
+
+        for row in &[funding, spending] {
+            let big = bincode_util::serialize_big(&row.key).unwrap();
+            let little = bincode_util::serialize_little(&row.key).unwrap();
+            assert!(verify_history_key(&big, confirmed).is_ok());
+            // little-endian keys either fail to parse or decode to a byte-swapped height
+            assert!(verify_history_key(&little, confirmed).is_err());
+
+            // a row at another height than the tx's only best-chain confirmation is rejected
+            assert!(verify_history_key(&big, |_| (1, vec![200])).is_err());
+            // unless the tx also has confirmations outside the best chain, from an orphaned
+            // block or one above the indexed tip
+            assert!(verify_history_key(&big, |_| (2, vec![200])).is_ok());
+            assert!(verify_history_key(&big, |_| (1, vec![])).is_ok());
+        }
+    }
+
+    // a chain query over `len` empty blocks, backed by temporary DBs under `dir`
+    pub(crate) fn test_chain(dir: &Path, len: u32) -> ChainQuery {
+        let mut prev_blockhash = BlockHash::default();
+        let chain = (0..len)
+            .map(|time| {
+                let header = BlockHeader {
+                    version: 1,
+                    prev_blockhash,
+                    merkle_root: Default::default(),
+                    time,
+                    bits: 0x207fffff,
+                    nonce: 0,
+                };
+                prev_blockhash = header.block_hash();
+                header
+            })
+            .collect();
+        let mut headers = HeaderList::empty();
+        let entries = headers.order(chain);
+        headers.apply(entries);
+
+        let store = Store {
+            txstore_db: DB::open_unversioned(&dir.join("txstore")),
+            history_db: DB::open_unversioned(&dir.join("history")),
+            cache_db: DB::open_unversioned(&dir.join("cache")),
+            added_blockhashes: RwLock::new(HashSet::new()),
+            indexed_blockhashes: RwLock::new(HashSet::new()),
+            indexed_headers: RwLock::new(headers),
+            broken_headers: None,
+            tip_height: watch::channel(len as usize - 1).0,
+        };
+        ChainQuery {
+            store: Arc::new(store),
+            daemon: None,
+            light_mode: false,
+            duration: HistogramVec::new(HistogramOpts::new("test", "test"), &["name"]).unwrap(),
+            decode_failures: Counter::new("test", "test").unwrap(),
+            insane_stats_caches: Counter::new("test", "test").unwrap(),
+            network: Network::Tidecoin,
+        }
+    }
+
+    #[test]
+    fn test_prune_history() {
+        let dir = tempfile::tempdir().unwrap();
+        let chain = test_chain(dir.path(), 21);
+        let script = Script::new();
+        let scripthash = compute_script_hash(&script);
+
+        // confirm a tx at the given height, returning its txid
+        let confirm = |height: usize, lock_time: u32| {
+            let tx = Transaction {
+                version: 1,
+                lock_time,
+                input: vec![],
+                output: vec![],
+            };
+            let blockhash = full_hash(&chain.header_by_height(height).unwrap().hash()[..]);
+            chain.store.txstore_db.write(
+                vec![TxConfRow::new(&tx, blockhash).into_row()],
+                DBFlush::Disable,
+            );
+            full_hash(&tx.txid()[..])
+        };
+        let funding = |txid, value| {
+            TxHistoryInfo::Funding(FundingInfo {
+                txid,
+                vout: 0,
+                value,
+            })
+        };
+
+        // funded at 2 and 3, the first output spent at 4, funded again above the prune height
+        let (tx1, tx2, tx3, tx4) = (confirm(2, 1), confirm(3, 2), confirm(4, 3), confirm(15, 4));
+        let spending = TxHistoryInfo::Spending(SpendingInfo {
+            txid: tx3,
+            vin: 0,
+            prev_txid: tx1,
+            prev_vout: 0,
+            value: 1000,
+        });
+        let rows = vec![
+            TxHistoryRow::new(&script, 2, funding(tx1, 1000)),
+            TxHistoryRow::new(&script, 3, funding(tx2, 500)),
+            TxHistoryRow::new(&script, 4, spending),
+            TxHistoryRow::new(&script, 15, funding(tx4, 200)),
+        ];
+        chain.store.history_db.write(
+            rows.into_iter().map(TxHistoryRow::into_row).collect(),
+            DBFlush::Disable,
+        );
+
+        let utxos = || {
+            let mut utxos: Vec<_> = chain
+                .utxo(&scripthash, usize::MAX, DBFlush::Disable)
+                .unwrap()
+                .into_iter()
+                .map(|utxo| {
+                    (
+                        full_hash(&utxo.txid[..]),
+                        utxo.value,
+                        utxo.confirmed.unwrap().height,
+                    )
+                })
+                .collect();
+            utxos.sort();
+            utxos
+        };
+        let mut expected = vec![(tx2, 500, 3), (tx4, 200, 15)];
+        expected.sort();
+        assert_eq!(utxos(), expected);
+
+        // too close to the tip at 20
+        assert!(chain.prune_history(11).is_err());
+        assert_eq!(chain.prune_history(10).unwrap(), 1);
+
+        // only the entry above the prune height is left, but the balance is unchanged
+        assert_eq!(chain.history_iter_scan(b'H', &scripthash, 0).count(), 1);
+        assert_eq!(chain.pruned_height(&scripthash), Some(10));
+        assert_eq!(utxos(), expected);
+        let stats = chain.stats(&scripthash, DBFlush::Disable).unwrap();
+        assert_eq!(stats.tx_count, 4);
+        assert_eq!((stats.funded_txo_count, stats.funded_txo_sum), (3, 1700));
+        assert_eq!((stats.spent_txo_count, stats.spent_txo_sum), (1, 1000));
+
+        // without the caches, the pruned balance can't be recomputed
+        chain
+            .store
+            .cache_db
+            .delete(vec![UtxoCacheRow::key(&scripthash)]);
+        assert!(chain
+            .utxo(&scripthash, usize::MAX, DBFlush::Disable)
+            .is_err());
+    }
+
+    #[test]
+    fn test_corrupt_txo() {
+        let dir = tempfile::tempdir().unwrap();
+        let chain = test_chain(dir.path(), 1);
+        let outpoint = OutPoint {
+            txid: "8ce9d58d8b0ecf5a9ce1ba1e4bcb13e7ab1ba84e0b3fcc8a7cd9ffa8d6d7a1ec"
+                .parse()
+                .unwrap(),
+            vout: 0,
+        };
+        let key = TxOutRow::key(&outpoint);
+        chain.store.txstore_db.put(&key, &[0xff]);
+
+        // treated as missing and counted, rather than panicking
+        assert!(chain.lookup_txo(&outpoint).is_none());
+        let outpoints: BTreeSet<OutPoint> = vec![outpoint].into_iter().collect();
+        assert!(chain.lookup_avail_txos(&outpoints).is_empty());
+        assert_eq!(chain.decode_failures.get(), 2);
+    }
+
+    #[test]
+    fn test_duplicate_txid_rows() {
+        let coinbase = Transaction {
+            version: 1,
+            lock_time: 0,
+            input: vec![],
+            output: vec![],
+        };
+        let (block1, block2) = ([1; 32], [2; 32]);
+
+        // the same txid confirmed in two blocks keeps one confirmation row per block
+        let conf1 = TxConfRow::new(&coinbase, block1).into_row();
+        let conf2 = TxConfRow::new(&coinbase, block2).into_row();
+        assert_ne!(conf1.key, conf2.key);
+        let filter = TxConfRow::filter(&coinbase.txid()[..]);
+        assert!(conf1.key.starts_with(&filter) && conf2.key.starts_with(&filter));
+        assert_eq!(TxConfRow::from_row(conf2).key.blockhash, block2);
+
+        // while the raw tx row is shared
+        let row1 = TxRow::new(&coinbase).into_row();
+        let row2 = TxRow::new(&coinbase).into_row();
+        assert_eq!(row1.key, row2.key);
+        assert_eq!(row1.value, row2.value);
+    }
+}
@@ -1,11 +1,11 @@
 use crate::chain::{address, BlockHash, Network, OutPoint, Script, Transaction, TxIn, TxOut, Txid};
 use crate::config::{Config, VERSION_STRING};
-use crate::errors;
-use crate::new_index::{compute_script_hash, Query, SpendingInput, Utxo};
+use crate::errors::{self, ErrorKind};
+use crate::new_index::{compute_script_hash, ChainQuery, DBFlush, Query, SpendingInput, Utxo};
 use crate::util::{
     create_socket, electrum_merkle, extract_tx_prevouts, full_hash, get_innerscripts, get_tx_fee,
     has_prevout, is_coinbase, transaction_sigop_count, BlockHeaderMeta, BlockId, FullHash,
-    ScriptToAddr, ScriptToAsm, TransactionStatus,
+    HeaderEntry, ScriptToAddr, ScriptToAsm, TransactionStatus,
 };
 
 use {tidecoin::consensus::encode, std::str::FromStr};
@@ -14,6 +14,7 @@ use tidecoin::blockdata::opcodes;
 use tidecoin::hashes::hex::{FromHex, ToHex};
 use tidecoin::hashes::Error as HashError;
 use hex::{self, FromHexError};
+use hyper::server::conn::AddrStream;
 use hyper::service::{make_service_fn, service_fn};
 use hyper::{Body, Method, Response, Server, StatusCode};
 use tokio::sync::oneshot;
@@ -23,11 +24,14 @@ use std::fs;
 
 use serde::Serialize;
 use serde_json;
+use sha2::{Digest, Sha256};
 use std::collections::HashMap;
+use std::net::IpAddr;
 use std::num::ParseIntError;
 use std::os::unix::fs::FileTypeExt;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 use std::thread;
+use std::time::{Duration, Instant};
 use url::form_urlencoded;
 
 const ADDRESS_SEARCH_LIMIT: usize = 10;
@@ -120,6 +124,8 @@ struct TransactionValue {
     fee: u64,
     #[serde(skip_serializing_if = "Option::is_none")]
     status: Option<TransactionStatus>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    first_seen: Option<u64>,
 }
 
 impl TransactionValue {
@@ -161,6 +167,7 @@ impl TransactionValue {
             sigops,
             fee,
             status: Some(TransactionStatus::from(blockid)),
+            first_seen: None,
         })
     }
 }
@@ -335,6 +342,14 @@ impl From<SpendingInput> for SpendingValue {
     }
 }
 
+// The spend of an output, with `confirmed` telling whether it was found in the chain or the mempool
+#[derive(Serialize)]
+struct OutputSpendValue {
+    #[serde(flatten)]
+    spend: SpendingValue,
+    confirmed: bool,
+}
+
 fn ttl_by_depth(height: Option<usize>, query: &Query) -> u32 {
     height.map_or(TTL_SHORT, |height| {
         if query.chain().best_height() - height >= CONF_FINAL {
@@ -345,6 +360,68 @@ fn ttl_by_depth(height: Option<usize>, query: &Query) -> u32 {
     })
 }
 
+// the best chain block at `height`, heights past the tip are not found
+fn header_by_height(chain: &ChainQuery, height: usize) -> Result<HeaderEntry, HttpError> {
+    chain
+        .header_by_height(height)
+        .ok_or_else(|| HttpError::not_found("Block not found".to_string()))
+}
+
+/// Per-IP rate limiting and duplicate suppression for transaction broadcasts
+struct BroadcastLimiter {
+    limit: usize,
+    window: Duration,
+    dedup_window: Duration,
+    requests: Mutex<HashMap<IpAddr, (Instant, usize)>>, // ip -> (window start, # of broadcasts)
+    recent: Mutex<HashMap<FullHash, (Instant, Txid)>>,  // sha256(txhex) -> (broadcast time, txid)
+}
+
+impl BroadcastLimiter {
+    fn new(config: &Config) -> Self {
+        BroadcastLimiter {
+            limit: config.broadcast_rate_limit,
+            window: Duration::from_secs(config.broadcast_rate_window),
+            dedup_window: Duration::from_secs(config.broadcast_dedup_window),
+            requests: Mutex::new(HashMap::new()),
+            recent: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Returns false if the ip already used up its broadcasts for the current window
+    fn allow(&self, ip: IpAddr) -> bool {
+        if self.limit == 0 {
+            return true;
+        }
+        let now = Instant::now();
+        let mut requests = self.requests.lock().unwrap();
+        requests.retain(|_, (start, _)| now.duration_since(*start) < self.window);
+        let (_, count) = requests.entry(ip).or_insert((now, 0));
+        if *count >= self.limit {
+            return false;
+        }
+        *count += 1;
+        true
+    }
+
+    /// Returns the txid if the same payload was successfully broadcast recently
+    fn recent_broadcast(&self, txhex: &str) -> Option<Txid> {
+        let mut recent = self.recent.lock().unwrap();
+        recent.retain(|_, (time, _)| time.elapsed() < self.dedup_window);
+        recent.get(&payload_hash(txhex)).map(|(_, txid)| *txid)
+    }
+
+    fn add_broadcast(&self, txhex: &str, txid: Txid) {
+        if !self.dedup_window.is_zero() {
+            let mut recent = self.recent.lock().unwrap();
+            recent.insert(payload_hash(txhex), (Instant::now(), txid));
+        }
+    }
+}
+
+fn payload_hash(txhex: &str) -> FullHash {
+    full_hash(&Sha256::digest(txhex.trim().to_lowercase().as_bytes())[..])
+}
+
 /// Prepare transactions to be serialized in a JSON response
 ///
 /// Any transactions with missing prevouts will be filtered out of the response, rather than returned with incorrect data.
@@ -370,38 +447,70 @@ fn prepare_txs(
         .collect()
 }
 
-#[tokio::main]
-async fn run_server(config: Arc<Config>, query: Arc<Query>, rx: oneshot::Receiver<()>) {
+fn run_server(config: Arc<Config>, query: Arc<Query>, rx: oneshot::Receiver<()>) {
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .worker_threads(config.rest_worker_threads)
+        .thread_name("rest-worker")
+        .enable_all()
+        .build()
+        .expect("failed to build the REST server runtime");
+    runtime.block_on(serve(config, query, rx));
+}
+
+async fn serve(config: Arc<Config>, query: Arc<Query>, rx: oneshot::Receiver<()>) {
     let addr = &config.http_addr;
     let socket_file = &config.http_socket_file;
 
     let config = Arc::clone(&config);
     let query = Arc::clone(&query);
+    let limiter = Arc::new(BroadcastLimiter::new(&config));
 
-    let make_service_fn_inn = || {
+    // remote_ip is None for unix socket connections
+    let make_service_fn_inn = |remote_ip: Option<IpAddr>| {
         let query = Arc::clone(&query);
         let config = Arc::clone(&config);
+        let limiter = Arc::clone(&limiter);
 
         async move {
             Ok::<_, hyper::Error>(service_fn(move |req| {
                 let query = Arc::clone(&query);
                 let config = Arc::clone(&config);
+                let limiter = Arc::clone(&limiter);
 
                 async move {
                     let method = req.method().clone();
                     let uri = req.uri().clone();
+                    let accept_binary = req
+                        .headers()
+                        .get(hyper::header::ACCEPT)
+                        .and_then(|value| value.to_str().ok())
+                        .map_or(false, |value| value.contains("application/octet-stream"));
                     let body = hyper::body::to_bytes(req.into_body()).await?;
 
-                    let mut resp = handle_request(method, uri, body, &query, &config)
-                        .unwrap_or_else(|err| {
-                            warn!("{:?}", err);
-                            Response::builder()
-                                .status(err.0)
-                                .header("Content-Type", "text/plain")
-                                .header("X-Powered-By", &**VERSION_STRING)
-                                .body(Body::from(err.1))
-                                .unwrap()
-                        });
+                    let resp = if method == Method::GET && uri.path() == "/blocks/tip/wait" {
+                        // long-polling has to be handled asynchronously
+                        wait_for_tip(uri, &query, &config).await
+                    } else {
+                        handle_request(
+                            method,
+                            uri,
+                            body,
+                            remote_ip,
+                            accept_binary,
+                            &query,
+                            &config,
+                            &limiter,
+                        )
+                    };
+                    let mut resp = resp.unwrap_or_else(|err| {
+                        warn!("{:?}", err);
+                        Response::builder()
+                            .status(err.0)
+                            .header("Content-Type", "text/plain")
+                            .header("X-Powered-By", &**VERSION_STRING)
+                            .body(Body::from(err.1))
+                            .unwrap()
+                    });
                     if let Some(ref origins) = config.cors {
                         resp.headers_mut()
                             .insert("Access-Control-Allow-Origin", origins.parse().unwrap());
@@ -421,7 +530,9 @@ async fn run_server(config: Arc<Config>, query: Arc<Query>, rx: oneshot::Receive
 
             Server::from_tcp(socket.into())
                 .expect("Server::from_tcp failed")
-                .serve(make_service_fn(move |_| make_service_fn_inn()))
+                .serve(make_service_fn(move |conn: &AddrStream| {
+                    make_service_fn_inn(Some(conn.remote_addr().ip()))
+                }))
                 .with_graceful_shutdown(async {
                     rx.await.ok();
                 })
@@ -437,13 +548,16 @@ async fn run_server(config: Arc<Config>, query: Arc<Query>, rx: oneshot::Receive
 
             info!("REST server running on unix socket {}", path.display());
 
-            Server::bind_unix(path)
+            let result = Server::bind_unix(path)
                 .expect("Server::bind_unix failed")
-                .serve(make_service_fn(move |_| make_service_fn_inn()))
+                .serve(make_service_fn(move |_| make_service_fn_inn(None)))
                 .with_graceful_shutdown(async {
                     rx.await.ok();
                 })
-                .await
+                .await;
+            // Don't leave a stale socket file behind
+            fs::remove_file(path).ok();
+            result
         }
     };
 
@@ -475,12 +589,52 @@ impl Handle {
     }
 }
 
+/// Wait until the indexed tip is past the `current` height (or until the timeout expires)
+/// and return the new tip height.
+async fn wait_for_tip(
+    uri: hyper::Uri,
+    query: &Query,
+    config: &Config,
+) -> Result<Response<Body>, HttpError> {
+    let query_params = match uri.query() {
+        Some(value) => form_urlencoded::parse(value.as_bytes())
+            .into_owned()
+            .collect::<HashMap<String, String>>(),
+        None => HashMap::new(),
+    };
+    let current = query_params
+        .get("current")
+        .ok_or_else(|| HttpError::from("Missing current height".to_string()))?
+        .parse::<usize>()?;
+    let timeout = query_params
+        .get("timeout")
+        .map_or(Ok(config.rest_max_tip_wait), |t| t.parse::<u64>())?
+        .min(config.rest_max_tip_wait);
+
+    info!("handle {:?} {:?}", Method::GET, uri);
+    let mut tip_height = query.chain().store().subscribe_tip_height();
+    let wait = async {
+        while *tip_height.borrow() <= current {
+            if tip_height.changed().await.is_err() {
+                break;
+            }
+        }
+    };
+    // the current height is returned either way, timing out is not an error
+    let _ = tokio::time::timeout(Duration::from_secs(timeout), wait).await;
+
+    http_message(StatusCode::OK, query.chain().best_height().to_string(), 0)
+}
+
 fn handle_request(
     method: Method,
     uri: hyper::Uri,
     body: hyper::body::Bytes,
+    remote_ip: Option<IpAddr>,
+    accept_binary: bool,
     query: &Query,
     config: &Config,
+    limiter: &BroadcastLimiter,
 ) -> Result<Response<Body>, HttpError> {
     // TODO it looks hyper does not have routing and query parsing :(
     let path: Vec<&str> = uri.path().split('/').skip(1).collect();
@@ -490,6 +644,9 @@ fn handle_request(
             .collect::<HashMap<String, String>>(),
         None => HashMap::new(),
     };
+    // return raw bytes rather than hex, for the endpoints that support it
+    let binary = accept_binary || query_params.get("format").map_or(false, |f| f == "bin");
+    let json = query_params.get("format").map_or(false, |f| f == "json");
 
     info!("handle {:?} {:?}", method, uri);
     match (
@@ -500,11 +657,16 @@ fn handle_request(
         path.get(3),
         path.get(4),
     ) {
-        (&Method::GET, Some(&"blocks"), Some(&"tip"), Some(&"hash"), None, None) => http_message(
-            StatusCode::OK,
-            query.chain().best_hash().to_hex(),
-            TTL_SHORT,
-        ),
+        (&Method::GET, Some(&"blocks"), Some(&"tip"), Some(&"hash"), None, None) => {
+            let tip = query.chain().best_header();
+            if json {
+                return json_response(
+                    json!({ "height": tip.height(), "hash": tip.hash() }),
+                    TTL_SHORT,
+                );
+            }
+            http_message(StatusCode::OK, tip.hash().to_hex(), TTL_SHORT)
+        }
 
         (&Method::GET, Some(&"blocks"), Some(&"tip"), Some(&"height"), None, None) => http_message(
             StatusCode::OK,
@@ -512,6 +674,44 @@ fn handle_request(
             TTL_SHORT,
         ),
 
+        (&Method::GET, Some(&"blocks"), Some(height), Some(&"hash"), None, None) => {
+            let height = height.parse::<usize>()?;
+            let header = header_by_height(query.chain(), height)?;
+            let ttl = ttl_by_depth(Some(height), query);
+            if json {
+                return json_response(json!({ "height": height, "hash": header.hash() }), ttl);
+            }
+            http_message(StatusCode::OK, header.hash().to_hex(), ttl)
+        }
+        (
+            &Method::GET,
+            Some(&"blocks"),
+            Some(&"headers"),
+            Some(start_height),
+            Some(count),
+            None,
+        ) => {
+            let start_height = start_height.parse::<usize>()?;
+            let count = count.parse::<usize>()?.min(config.rest_max_headers);
+            if start_height > query.chain().best_height() {
+                return Err(HttpError::not_found("Block not found".to_string()));
+            }
+            let headers = query.chain().headers_by_height(start_height, count);
+            let ttl = ttl_by_depth(headers.last().map(|h| h.height()), query);
+
+            if json {
+                let headers: Vec<String> = headers
+                    .iter()
+                    .map(|h| hex::encode(encode::serialize(h.header())))
+                    .collect();
+                return json_response(headers, ttl);
+            }
+            let raw: Vec<u8> = headers
+                .iter()
+                .flat_map(|h| encode::serialize(h.header()))
+                .collect();
+            hex_or_raw_response(raw, binary, ttl)
+        }
         (&Method::GET, Some(&"blocks"), start_height, None, None, None) => {
             let start_height = start_height.and_then(|height| height.parse::<usize>().ok());
             blocks(query, config, start_height)
@@ -536,7 +736,10 @@ fn handle_request(
         }
         (&Method::GET, Some(&"block"), Some(hash), Some(&"status"), None, None) => {
             let hash = BlockHash::from_hex(hash)?;
-            let status = query.chain().get_block_status(&hash);
+            let status = query
+                .chain()
+                .get_block_status(&hash)
+                .ok_or_else(|| HttpError::not_found("Block not found".to_string()))?;
             let ttl = ttl_by_depth(status.height, query);
             json_response(status, ttl)
         }
@@ -567,8 +770,7 @@ fn handle_request(
                 .get_block_header(&hash)
                 .ok_or_else(|| HttpError::not_found("Block not found".to_string()))?;
 
-            let header_hex = hex::encode(encode::serialize(&header));
-            http_message(StatusCode::OK, header_hex, TTL_LONG)
+            hex_or_raw_response(encode::serialize(&header), binary, TTL_LONG)
         }
         (&Method::GET, Some(&"block"), Some(hash), Some(&"raw"), None, None) => {
             let hash = BlockHash::from_hex(hash)?;
@@ -577,13 +779,7 @@ fn handle_request(
                 .get_block_raw(&hash)
                 .ok_or_else(|| HttpError::not_found("Block not found".to_string()))?;
 
-            Ok(Response::builder()
-                .status(StatusCode::OK)
-                .header("Content-Type", "application/octet-stream")
-                .header("Cache-Control", format!("public, max-age={:}", TTL_LONG))
-                .header("X-Powered-By", &**VERSION_STRING)
-                .body(Body::from(raw))
-                .unwrap())
+            raw_response(raw, TTL_LONG)
         }
         (&Method::GET, Some(&"block"), Some(hash), Some(&"txid"), Some(index), None) => {
             let hash = BlockHash::from_hex(hash)?;
@@ -640,7 +836,7 @@ fn handle_request(
         (&Method::GET, Some(script_type @ &"address"), Some(script_str), None, None, None)
         | (&Method::GET, Some(script_type @ &"scripthash"), Some(script_str), None, None, None) => {
             let script_hash = to_scripthash(script_type, script_str, config.network_type)?;
-            let stats = query.stats(&script_hash[..]);
+            let stats = query.stats(&script_hash[..])?;
             json_response(
                 json!({
                     *script_type: script_str,
@@ -676,6 +872,8 @@ fn handle_request(
                 .and_then(|s| s.parse::<Txid>().ok());
 
             let mut txs = vec![];
+            // whether the after_txid cursor is past the mempool, in the confirmed history
+            let mut after_confirmed = false;
 
             if let Some(given_txid) = &after_txid {
                 let is_mempool = query
@@ -696,23 +894,26 @@ fn handle_request(
                         String::from("after_txid not found"),
                     ));
                 }
+                after_confirmed = is_confirmed;
             }
 
-            txs.extend(
-                query
-                    .mempool()
-                    .history(&script_hash[..], after_txid.as_ref(), max_txs)
-                    .into_iter()
-                    .map(|tx| (tx, None)),
-            );
+            if !after_confirmed {
+                txs.extend(
+                    query
+                        .mempool()
+                        .history(&script_hash[..], after_txid.as_ref(), max_txs)
+                        .into_iter()
+                        .map(|tx| (tx, None)),
+                );
+            }
 
             if txs.len() < max_txs {
-                let after_txid_ref = if !txs.is_empty() {
-                    // If there are any txs, we know mempool found the
-                    // after_txid IF it exists... so always return None.
-                    None
-                } else {
+                // Continue from the start of the confirmed history, unless the cursor is a
+                // confirmed tx. This also holds when the cursor was the last mempool tx.
+                let after_txid_ref = if after_confirmed {
                     after_txid.as_ref()
+                } else {
+                    None
                 };
                 txs.extend(
                     query
@@ -807,14 +1008,53 @@ fn handle_request(
             None,
         ) => {
             let script_hash = to_scripthash(script_type, script_str, config.network_type)?;
+            // cache=false writes the updated utxo cache without syncing it to disk, saving write
+            // amplification for read-heavy clients. The cache may then be lost on restart, making
+            // subsequent requests for the same script slower as the utxo set gets recomputed.
+            let flush = match query_params.get("cache").map(String::as_str) {
+                Some("false") => DBFlush::Disable,
+                _ => DBFlush::Enable,
+            };
             let utxos: Vec<UtxoValue> = query
-                .utxo(&script_hash[..])?
+                .utxo(&script_hash[..], flush)?
                 .into_iter()
                 .map(UtxoValue::from)
                 .collect();
             // XXX paging?
             json_response(utxos, TTL_SHORT)
         }
+        (
+            &Method::GET,
+            Some(script_type @ &"address"),
+            Some(script_str),
+            Some(&"first-use"),
+            None,
+            None,
+        )
+        | (
+            &Method::GET,
+            Some(script_type @ &"scripthash"),
+            Some(script_str),
+            Some(&"first-use"),
+            None,
+            None,
+        ) => {
+            let script_hash = to_scripthash(script_type, script_str, config.network_type)?;
+            let (txid, blockid) = query
+                .chain()
+                .get_first_use(&script_hash[..])
+                .ok_or_else(|| HttpError::not_found("No confirmed history".to_string()))?;
+            let ttl = ttl_by_depth(Some(blockid.height), query);
+            json_response(
+                json!({
+                    "txid": txid,
+                    "block_height": blockid.height,
+                    "block_hash": blockid.hash,
+                    "block_time": blockid.time,
+                }),
+                ttl,
+            )
+        }
         (&Method::GET, Some(&"address-prefix"), Some(prefix), None, None, None) => {
             if !config.address_search {
                 return Err(HttpError::from("address search disabled".to_string()));
@@ -839,7 +1079,9 @@ fn handle_request(
                     0,
                 )
             } else {
-                json_response(tx.remove(0), ttl)
+                let mut tx = tx.remove(0);
+                tx.first_seen = query.mempool().get_first_seen(&hash);
+                json_response(tx, ttl)
             }
         }
         (&Method::GET, Some(&"tx"), Some(hash), Some(out_type @ &"hex"), None, None)
@@ -849,20 +1091,13 @@ fn handle_request(
                 .lookup_raw_txn(&hash)
                 .ok_or_else(|| HttpError::not_found("Transaction not found".to_string()))?;
 
-            let (content_type, body) = match *out_type {
-                "raw" => ("application/octet-stream", Body::from(rawtx)),
-                "hex" => ("text/plain", Body::from(hex::encode(rawtx))),
-                _ => unreachable!(),
-            };
             let ttl = ttl_by_depth(query.get_tx_status(&hash).block_height, query);
 
-            Ok(Response::builder()
-                .status(StatusCode::OK)
-                .header("Content-Type", content_type)
-                .header("Cache-Control", format!("public, max-age={:}", ttl))
-                .header("X-Powered-By", &**VERSION_STRING)
-                .body(body)
-                .unwrap())
+            match *out_type {
+                "raw" => raw_response(rawtx, ttl),
+                "hex" => hex_or_raw_response(rawtx, binary, ttl),
+                _ => unreachable!(),
+            }
         }
         (&Method::GET, Some(&"tx"), Some(hash), Some(&"status"), None, None) => {
             let hash = Txid::from_hex(hash)?;
@@ -896,9 +1131,9 @@ fn handle_request(
                 .chain()
                 .height_by_hash(&merkleblock.header.block_hash());
 
-            http_message(
-                StatusCode::OK,
-                hex::encode(encode::serialize(&merkleblock)),
+            hex_or_raw_response(
+                encode::serialize(&merkleblock),
+                binary,
                 ttl_by_depth(height, query),
             )
         }
@@ -930,6 +1165,20 @@ fn handle_request(
             // @TODO long ttl if all outputs are either spent long ago or unspendable
             json_response(spends, TTL_SHORT)
         }
+        (&Method::GET, Some(&"output"), Some(outpoint), Some(&"spend"), None, None) => {
+            let outpoint = OutPoint::from_str(outpoint)
+                .map_err(|_| HttpError::from("Invalid outpoint".to_string()))?;
+            let spend = query.lookup_spend(&outpoint);
+            let confirmed = spend
+                .as_ref()
+                .map_or(false, |spend| spend.confirmed.is_some());
+            let spend = spend.map_or_else(SpendingValue::default, SpendingValue::from);
+            let ttl = ttl_by_depth(
+                spend.status.as_ref().and_then(|status| status.block_height),
+                query,
+            );
+            json_response(OutputSpendValue { spend, confirmed }, ttl)
+        }
         (&Method::GET, Some(&"broadcast"), None, None, None, None)
         | (&Method::POST, Some(&"tx"), None, None, None, None) => {
             // accept both POST and GET for backward compatibility.
@@ -942,10 +1191,35 @@ fn handle_request(
                     .ok_or_else(|| HttpError::from("Missing tx".to_string()))?,
                 _ => return http_message(StatusCode::METHOD_NOT_ALLOWED, "Invalid method", 0),
             };
-            let txid = query
-                .broadcast_raw(&txhex)
-                .map_err(|err| HttpError::from(err.description().to_string()))?;
-            http_message(StatusCode::OK, txid.to_hex(), 0)
+            if let Some(txid) = limiter.recent_broadcast(&txhex) {
+                return http_message(StatusCode::OK, txid.to_hex(), 0);
+            }
+            if let Some(ip) = remote_ip {
+                if !limiter.allow(ip) {
+                    return http_message(
+                        StatusCode::TOO_MANY_REQUESTS,
+                        "Too many broadcasts, try again later",
+                        0,
+                    );
+                }
+            }
+            match query.broadcast_raw(&txhex) {
+                Ok(txid) => {
+                    limiter.add_broadcast(&txhex, txid);
+                    http_message(StatusCode::OK, txid.to_hex(), 0)
+                }
+                // pass the node's reject reason on as-is
+                Err(errors::Error(ErrorKind::RpcError(_, code, message), _)) => {
+                    let body = json!({ "error": { "code": code, "message": message } });
+                    Ok(Response::builder()
+                        .status(StatusCode::BAD_REQUEST)
+                        .header("Content-Type", "application/json")
+                        .header("X-Powered-By", &**VERSION_STRING)
+                        .body(Body::from(body.to_string()))
+                        .unwrap())
+                }
+                Err(err) => Err(HttpError::from(err.description().to_string())),
+            }
         }
         (&Method::GET, Some(&"txs"), Some(&"outspends"), None, None, None) => {
             let txid_strings: Vec<&str> = query_params
@@ -986,6 +1260,15 @@ fn handle_request(
         (&Method::GET, Some(&"mempool"), Some(&"txids"), None, None, None) => {
             json_response(query.mempool().txids(), TTL_SHORT)
         }
+        (&Method::GET, Some(&"mempool"), Some(&"txids"), Some(&"page"), last_seen_txid, None) => {
+            let last_seen_txid = last_seen_txid.and_then(|txid| Txid::from_hex(txid).ok());
+            json_response(
+                query
+                    .mempool()
+                    .txids_page(config.rest_mempool_page_size, last_seen_txid),
+                TTL_SHORT,
+            )
+        }
         (&Method::GET, Some(&"mempool"), Some(&"txs"), Some(&"all"), None, None) => {
             let txs = query
                 .mempool()
@@ -1023,7 +1306,7 @@ fn handle_request(
             let last_seen_txid = last_seen_txid.and_then(|txid| Txid::from_hex(txid).ok());
             let txs = query
                 .mempool()
-                .txs_page(10_000, last_seen_txid)
+                .txs_page(config.rest_mempool_page_size, last_seen_txid)
                 .into_iter()
                 .map(|tx| (tx, None))
                 .collect();
@@ -1060,6 +1343,33 @@ where
         .unwrap())
 }
 
+fn raw_response(bytes: Vec<u8>, ttl: u32) -> Result<Response<Body>, HttpError> {
+    Ok(Response::builder()
+        .status(StatusCode::OK)
+        .header("Content-Type", "application/octet-stream")
+        .header("Cache-Control", format!("public, max-age={:}", ttl))
+        .header("X-Powered-By", &**VERSION_STRING)
+        .body(Body::from(bytes))
+        .unwrap())
+}
+
+// For the endpoints that return hex by default but raw bytes when asked to through the Accept
+// header. The body differs by Accept for the same URL, which shared caches must be told about.
+fn hex_or_raw_response(
+    bytes: Vec<u8>,
+    binary: bool,
+    ttl: u32,
+) -> Result<Response<Body>, HttpError> {
+    let mut resp = if binary {
+        raw_response(bytes, ttl)?
+    } else {
+        http_message(StatusCode::OK, hex::encode(bytes), ttl)?
+    };
+    resp.headers_mut()
+        .insert(hyper::header::VARY, "Accept".parse().unwrap());
+    Ok(resp)
+}
+
 fn json_response<T: Serialize>(value: T, ttl: u32) -> Result<Response<Body>, HttpError> {
     let value = serde_json::to_string(&value)?;
     Ok(Response::builder()
@@ -1214,8 +1524,8 @@ impl From<tidecoin::util::address::Error> for HttpError {
 impl From<errors::Error> for HttpError {
     fn from(e: errors::Error) -> Self {
         warn!("errors::Error: {:?}", e);
-        match e.description().to_string().as_ref() {
-            "getblock RPC error: {\"code\":-5,\"message\":\"Block not found\"}" => {
+        match e.kind() {
+            ErrorKind::RpcError(method, -5, _) if method == "getblock" => {
                 HttpError::not_found("Block not found".to_string())
             }
             _ => HttpError::from(e.to_string()),
@@ -1429,4 +1739,21 @@ mod tests {
             );
         }
     }
+
+    #[test]
+    fn test_header_by_height_at_tip() {
+        use super::header_by_height;
+        use crate::new_index::schema::tests::test_chain;
+        use hyper::StatusCode;
+
+        let dir = tempfile::tempdir().unwrap();
+        let chain = test_chain(dir.path(), 3);
+
+        // the tip is served, one past it is a 404
+        let tip = header_by_height(&chain, 2).unwrap();
+        assert_eq!(*tip.hash(), chain.best_hash());
+        let err = header_by_height(&chain, 3).unwrap_err();
+        assert_eq!(err.0, StatusCode::NOT_FOUND);
+        assert!(header_by_height(&chain, usize::MAX).is_err());
+    }
 }
@@ -9,6 +9,13 @@
 //! | TxHistoryRow | big    | fixed      | allow          | unlimited  |
 //! | All others   | little | fixed      | allow          | unlimited  |
 //! +--------------+--------+------------+----------------+------------+
+//!
+//! TxHistoryRow keys are scanned by height (e.g. `history_iter_scan`), which only
+//! matches the byte order of the keys when the height is big-endian. The other rows
+//! (TxRow, TxConfRow, TxOutRow, BlockRow, TxEdgeRow and the utxo/stats cache rows)
+//! are only ever looked up by exact key or prefix, so their endianness doesn't affect
+//! scans, but changing it would still make existing DBs unreadable.
+//! `Store::verify_history_encoding` checks a sample of the history rows on startup.
 
 // We only want people to use bincode_util
 use bincode::Options;
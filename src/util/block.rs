@@ -77,10 +77,11 @@ impl HeaderList {
         }
     }
 
+    /// Build the chain ending at `tip_hash`, failing if it doesn't connect back to genesis
     pub fn new(
-        mut headers_map: HashMap<BlockHash, BlockHeader>,
+        headers_map: &HashMap<BlockHash, BlockHeader>,
         tip_hash: BlockHash,
-    ) -> HeaderList {
+    ) -> Result<HeaderList> {
         trace!(
             "processing {} headers, tip at {:?}",
             headers_map.len(),
@@ -92,13 +93,14 @@ impl HeaderList {
         let null_hash = BlockHash::default();
 
         while blockhash != null_hash {
-            let header = headers_map.remove(&blockhash).unwrap_or_else(|| {
-                panic!(
+            let header = match headers_map.get(&blockhash) {
+                Some(header) => *header,
+                None => bail!(
                     "missing expected blockhash in headers map: {:?}, pointed from: {:?}",
                     blockhash,
                     headers_chain.last().map(|h| h.block_hash())
-                )
-            });
+                ),
+            };
             blockhash = header.prev_blockhash;
             headers_chain.push(header);
         }
@@ -107,12 +109,48 @@ impl HeaderList {
         trace!(
             "{} chained headers ({} orphan blocks left)",
             headers_chain.len(),
-            headers_map.len()
+            headers_map.len() - headers_chain.len()
         );
 
         let mut headers = HeaderList::empty();
         headers.apply(headers.order(headers_chain));
-        headers
+        Ok(headers)
+    }
+
+    /// Build the longest chain in `headers_map` that connects back to genesis
+    pub fn longest_chain(headers_map: &HashMap<BlockHash, BlockHeader>) -> HeaderList {
+        let null_hash = BlockHash::default();
+        // # of headers from genesis up to each block, None if one of them is missing
+        let mut lens: HashMap<BlockHash, Option<usize>> = HashMap::new();
+        for hash in headers_map.keys() {
+            let mut path = vec![];
+            let mut blockhash = *hash;
+            let mut len = loop {
+                if blockhash == null_hash {
+                    break Some(0);
+                }
+                if let Some(len) = lens.get(&blockhash) {
+                    break *len;
+                }
+                match headers_map.get(&blockhash) {
+                    Some(header) => {
+                        path.push(blockhash);
+                        blockhash = header.prev_blockhash;
+                    }
+                    None => break None,
+                }
+            };
+            for blockhash in path.into_iter().rev() {
+                len = len.map(|len| len + 1);
+                lens.insert(blockhash, len);
+            }
+        }
+        lens.into_iter()
+            .filter_map(|(hash, len)| Some((len?, hash)))
+            .max()
+            .map_or_else(HeaderList::empty, |(_, tip_hash)| {
+                HeaderList::new(headers_map, tip_hash).expect("connected chain")
+            })
     }
 
     pub fn order(&self, new_headers: Vec<BlockHeader>) -> Vec<HeaderEntry> {
@@ -155,7 +193,8 @@ impl HeaderList {
             .collect()
     }
 
-    pub fn apply(&mut self, new_headers: Vec<HeaderEntry>) {
+    /// Returns the entries that were removed from the best chain (i.e. orphaned by a reorg)
+    pub fn apply(&mut self, new_headers: Vec<HeaderEntry>) -> Vec<HeaderEntry> {
         // new_headers[i] -> new_headers[i - 1] (i.e. new_headers.last() is the tip)
         for i in 1..new_headers.len() {
             assert_eq!(new_headers[i - 1].height() + 1, new_headers[i].height());
@@ -175,14 +214,14 @@ impl HeaderList {
                 assert_eq!(entry.header().prev_blockhash, expected_prev_blockhash);
                 height
             }
-            None => return,
+            None => return vec![],
         };
         debug!(
             "applying {} new headers from height {}",
             new_headers.len(),
             new_height
         );
-        let _removed = self.headers.split_off(new_height); // keep [0..new_height) entries
+        let removed = self.headers.split_off(new_height); // keep [0..new_height) entries
         for new_header in new_headers {
             let height = new_header.height();
             assert_eq!(height, self.headers.len());
@@ -190,6 +229,14 @@ impl HeaderList {
             self.headers.push(new_header);
             self.heights.insert(self.tip, height);
         }
+        removed
+    }
+
+    /// Drop the entries at `height` and above, returning them
+    pub fn truncate(&mut self, height: usize) -> Vec<HeaderEntry> {
+        let removed = self.headers.split_off(height.min(self.headers.len()));
+        self.tip = self.headers.last().map(|h| *h.hash()).unwrap_or_default();
+        removed
     }
 
     pub fn header_by_blockhash(&self, blockhash: &BlockHash) -> Option<&HeaderEntry> {
@@ -233,21 +280,17 @@ impl HeaderList {
         self.headers.iter()
     }
 
-    /// Get the Median Time Past
-    pub fn get_mtp(&self, height: usize) -> u32 {
+    /// Get the Median Time Past, or None if `height` is beyond the tip
+    pub fn get_mtp(&self, height: usize) -> Option<u32> {
+        self.headers.get(height)?;
         // Use the timestamp as the mtp of the genesis block.
         // Matches bitcoind's behaviour: bitcoin-cli getblock `bitcoin-cli getblockhash 0` | jq '.time == .mediantime'
-        if height == 0 {
-            self.headers.get(0).unwrap().header.time
-        } else if height > self.len() - 1 {
-            0
-        } else {
-            let mut timestamps = (height.saturating_sub(MTP_SPAN - 1)..=height)
-                .map(|p_height| self.headers.get(p_height).unwrap().header.time)
-                .collect::<Vec<_>>();
-            timestamps.sort_unstable();
-            timestamps[timestamps.len() / 2]
-        }
+        let mut timestamps = self.headers[height.saturating_sub(MTP_SPAN - 1)..=height]
+            .iter()
+            .map(|entry| entry.header.time)
+            .collect::<Vec<_>>();
+        timestamps.sort_unstable();
+        Some(timestamps[timestamps.len() / 2])
     }
 }
 
@@ -321,3 +364,81 @@ impl BlockMeta {
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn header_chain(times: &[u32]) -> Vec<BlockHeader> {
+        let mut prev_blockhash = BlockHash::default();
+        times
+            .iter()
+            .map(|&time| {
+                let header = BlockHeader {
+                    version: 1,
+                    prev_blockhash,
+                    merkle_root: Default::default(),
+                    time,
+                    bits: 0x207fffff,
+                    nonce: 0,
+                };
+                prev_blockhash = header.block_hash();
+                header
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_heights_beyond_tip() {
+        let empty = HeaderList::empty();
+        assert!(empty.header_by_height(0).is_none());
+        assert_eq!(empty.get_mtp(0), None);
+
+        let mut headers = HeaderList::empty();
+        let entries = headers.order(header_chain(&[10, 30, 20]));
+        headers.apply(entries);
+        assert_eq!(headers.get_mtp(0), Some(10));
+        assert_eq!(headers.get_mtp(1), Some(30));
+        assert_eq!(headers.get_mtp(2), Some(20));
+        assert_eq!(headers.get_mtp(3), None);
+        assert!(headers.header_by_height(3).is_none());
+        assert!(headers.header_by_height(usize::MAX).is_none());
+    }
+
+    #[test]
+    fn test_truncate() {
+        let mut headers = HeaderList::empty();
+        let entries = headers.order(header_chain(&[1, 2, 3]));
+        let hashes: Vec<BlockHash> = entries.iter().map(|e| *e.hash()).collect();
+        headers.apply(entries);
+
+        let removed = headers.truncate(1);
+        assert_eq!(removed.len(), 2);
+        assert_eq!(*headers.tip(), hashes[0]);
+        assert!(headers.header_by_blockhash(&hashes[1]).is_none());
+
+        assert!(headers.truncate(5).is_empty());
+        headers.truncate(0);
+        assert!(headers.is_empty());
+        assert_eq!(*headers.tip(), BlockHash::default());
+    }
+
+    #[test]
+    fn test_broken_chain() {
+        let chain = header_chain(&[1, 2, 3, 4]);
+        let tip_hash = chain[3].block_hash();
+        let mut headers_map: HashMap<BlockHash, BlockHeader> =
+            chain.iter().map(|h| (h.block_hash(), *h)).collect();
+        assert_eq!(HeaderList::new(&headers_map, tip_hash).unwrap().len(), 4);
+        assert_eq!(HeaderList::longest_chain(&headers_map).len(), 4);
+
+        // with the header at height 2 missing, only heights 0 and 1 still connect
+        headers_map.remove(&chain[2].block_hash());
+        assert!(HeaderList::new(&headers_map, tip_hash).is_err());
+        let headers = HeaderList::longest_chain(&headers_map);
+        assert_eq!(headers.len(), 2);
+        assert_eq!(*headers.tip(), chain[1].block_hash());
+
+        assert!(HeaderList::longest_chain(&HashMap::new()).is_empty());
+    }
+}
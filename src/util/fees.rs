@@ -1,7 +1,7 @@
 use crate::chain::{Network, Transaction, TxOut};
 use std::collections::HashMap;
 
-const VSIZE_BIN_WIDTH: u32 = 50_000; // in vbytes
+pub const VSIZE_BIN_WIDTH: u32 = 50_000; // in vbytes, the default --fee-histogram-bin-width
 
 pub struct TxFeeInfo {
     pub fee: u64,   // in satoshis
@@ -32,14 +32,24 @@ pub fn get_tx_fee(tx: &Transaction, prevouts: &HashMap<u32, &TxOut>, _network: N
     total_in - total_out
 }
 
-pub fn make_fee_histogram(mut entries: Vec<&TxFeeInfo>) -> Vec<(f32, u32)> {
+/// Fee rates are in sat/vbyte. Without fixed `buckets`, bins are closed once they reach
+/// `bin_width` vbytes. Otherwise each bin starts at one of the given fee rates (plus 0).
+pub fn make_fee_histogram(
+    mut entries: Vec<&TxFeeInfo>,
+    bin_width: u32,
+    buckets: &[f32],
+) -> Vec<(f32, u32)> {
     entries.sort_unstable_by(|e1, e2| e1.fee_per_vbyte.partial_cmp(&e2.fee_per_vbyte).unwrap());
 
+    if !buckets.is_empty() {
+        return make_fixed_fee_histogram(&entries, buckets);
+    }
+
     let mut histogram = vec![];
     let mut bin_size = 0;
     let mut last_fee_rate = 0.0;
     for e in entries.iter().rev() {
-        if bin_size > VSIZE_BIN_WIDTH && last_fee_rate != e.fee_per_vbyte {
+        if bin_size > bin_width && last_fee_rate != e.fee_per_vbyte {
             // vsize of transactions paying >= last_fee_rate
             histogram.push((last_fee_rate, bin_size));
             bin_size = 0;
@@ -52,3 +62,78 @@ pub fn make_fee_histogram(mut entries: Vec<&TxFeeInfo>) -> Vec<(f32, u32)> {
     }
     histogram
 }
+
+// `entries` must be sorted by fee rate
+fn make_fixed_fee_histogram(entries: &[&TxFeeInfo], buckets: &[f32]) -> Vec<(f32, u32)> {
+    let mut buckets = buckets.to_vec();
+    buckets.push(0.0);
+    buckets.sort_unstable_by(|b1, b2| b2.partial_cmp(b1).unwrap());
+    buckets.dedup();
+
+    let mut histogram = vec![];
+    let mut bin_size = 0;
+    let mut bucket = 0;
+    for e in entries.iter().rev() {
+        while e.fee_per_vbyte < buckets[bucket] {
+            if bin_size > 0 {
+                // vsize of transactions paying >= this bucket's fee rate (and less than the previous one)
+                histogram.push((buckets[bucket], bin_size));
+                bin_size = 0;
+            }
+            bucket += 1;
+        }
+        bin_size += e.vsize;
+    }
+    if bin_size > 0 {
+        histogram.push((buckets[bucket], bin_size));
+    }
+    histogram
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fee_info(fee: u64, vsize: u32) -> TxFeeInfo {
+        TxFeeInfo {
+            fee,
+            vsize,
+            fee_per_vbyte: fee as f32 / vsize as f32,
+        }
+    }
+
+    #[test]
+    fn test_fee_histogram_totals() {
+        let feeinfo: Vec<TxFeeInfo> = (1..200u64)
+            .map(|i| fee_info(i * 37 % 1000, 150 + (i as u32 * 13) % 400))
+            .collect();
+        let total_vsize: u32 = feeinfo.iter().map(|f| f.vsize).sum();
+
+        let configs: Vec<(u32, Vec<f32>)> = vec![
+            (VSIZE_BIN_WIDTH, vec![]),
+            (1_000, vec![]),
+            (0, vec![]),
+            (VSIZE_BIN_WIDTH, vec![1.0, 2.0, 5.0]),
+            (VSIZE_BIN_WIDTH, vec![0.5, 100.0, 3.0, 3.0]),
+        ];
+        for (bin_width, buckets) in configs {
+            let histogram = make_fee_histogram(feeinfo.iter().collect(), bin_width, &buckets);
+            let vsize: u32 = histogram.iter().map(|(_, vsize)| vsize).sum();
+            assert_eq!(vsize, total_vsize, "{} {:?}", bin_width, buckets);
+            // fee rates are strictly decreasing
+            assert!(histogram.windows(2).all(|w| w[0].0 > w[1].0));
+        }
+    }
+
+    #[test]
+    fn test_fixed_fee_histogram() {
+        let feeinfo = vec![
+            fee_info(100, 100), // 1 sat/vb
+            fee_info(300, 100), // 3 sat/vb
+            fee_info(250, 50),  // 5 sat/vb
+            fee_info(50, 100),  // 0.5 sat/vb
+        ];
+        let histogram = make_fee_histogram(feeinfo.iter().collect(), VSIZE_BIN_WIDTH, &[2.0, 1.0]);
+        assert_eq!(histogram, vec![(2.0, 150), (1.0, 100), (0.0, 100)]);
+    }
+}
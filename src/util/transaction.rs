@@ -14,6 +14,8 @@ pub struct TransactionStatus {
     pub block_hash: Option<BlockHash>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub block_time: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub replaced_by: Option<Txid>,
 }
 
 impl From<Option<BlockId>> for TransactionStatus {
@@ -24,12 +26,14 @@ impl From<Option<BlockId>> for TransactionStatus {
                 block_height: Some(b.height),
                 block_hash: Some(b.hash),
                 block_time: Some(b.time),
+                replaced_by: None,
             },
             None => TransactionStatus {
                 confirmed: false,
                 block_height: None,
                 block_hash: None,
                 block_time: None,
+                replaced_by: None,
             },
         }
     }